@@ -0,0 +1,55 @@
+use futures::channel::oneshot;
+use futures::future::{self, Either, Shared};
+use futures::prelude::*;
+
+use super::error::Error;
+
+pub fn cancellation() -> (Canceller, Cancellation) {
+    let (sender, receiver) = oneshot::channel();
+    (Canceller(sender), Cancellation(receiver.shared()))
+}
+
+#[derive(Debug)]
+pub struct Canceller(oneshot::Sender<()>);
+
+impl Canceller {
+    pub fn cancel(self) {
+        // nothing to cancel if all the cancellations have been dropped
+        let _ = self.0.send(());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cancellation(Shared<oneshot::Receiver<()>>);
+
+impl Cancellation {
+    /// Completes when cancelled, or never completes if the canceller is dropped without cancelling
+    pub async fn cancelled(&self) {
+        if self.0.clone().await.is_err() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+/// Drives the future unless cancelled, and drops it with `Error::Cancelled` if cancelled
+pub(crate) async fn unless_cancelled<F, T>(
+    cancellation: Option<&Cancellation>,
+    fut: F,
+) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    let cancelled = async {
+        match cancellation {
+            Some(cancellation) => cancellation.cancelled().await,
+            None => future::pending().await,
+        }
+    };
+    futures::pin_mut!(fut);
+    futures::pin_mut!(cancelled);
+    // the cancellation is checked first not to start if already cancelled
+    match future::select(cancelled, fut).await {
+        Either::Left(_) => Err(Error::Cancelled),
+        Either::Right((result, _)) => result,
+    }
+}
@@ -73,22 +73,6 @@ impl<S> ChanExec<S> {
     }
 }
 
-pub trait Work<Q, S> {
-    type Fut: std::future::Future<Output = S>;
-    fn work(&mut self, req: Q) -> Self::Fut;
-}
-
-impl<Q, S, F, Fut> Work<Q, S> for F
-where
-    F: FnMut(Q) -> Fut,
-    Fut: std::future::Future<Output = S>,
-{
-    type Fut = Fut;
-    fn work(&mut self, req: Q) -> Self::Fut {
-        self(req)
-    }
-}
-
 pub fn create<S>(queue_size: usize) -> (
     ChanExec<S>,
     impl Stream<Item = impl Future<Output = Result<(), S>>>,
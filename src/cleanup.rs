@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures::prelude::*;
+
+use super::key_resolver;
+use super::store::{MultipartUploadInfo, ObjectStore};
+use super::utils::with_retry;
+use super::{Error, ErrorKind};
+
+#[derive(Debug, Clone)]
+pub struct ArchiveCleanup {
+    pub s3_bucket: String,
+    pub s3_prefix: String,
+    pub older_than: Duration,
+    pub dry_run: bool,
+}
+
+impl Default for ArchiveCleanup {
+    fn default() -> Self {
+        ArchiveCleanup {
+            s3_bucket: String::new(),
+            s3_prefix: String::new(),
+            // the uploads of the running jobs are kept
+            older_than: Duration::from_secs(24 * 60 * 60),
+            dry_run: false,
+        }
+    }
+}
+
+pub type UploadCallback = Arc<dyn Fn(&MultipartUploadInfo) + Send + Sync>;
+
+pub struct CleanupExecutor {
+    store: Arc<dyn ObjectStore>,
+    on_upload: Option<UploadCallback>,
+}
+
+impl CleanupExecutor {
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            on_upload: None,
+        }
+    }
+
+    /// Calls the callback with each upload to abort before aborting it, or instead if dry run
+    pub fn on_upload(mut self, callback: UploadCallback) -> Self {
+        self.on_upload = Some(callback);
+        self
+    }
+
+    pub async fn execute(
+        &self,
+        ArchiveCleanup {
+            s3_bucket,
+            s3_prefix,
+            older_than,
+            dry_run,
+        }: ArchiveCleanup,
+    ) -> Result<(), Error> {
+        let threshold = SystemTime::now() - older_than;
+        let data_prefix = key_resolver::data_prefix(&s3_prefix);
+        let mut markers = None;
+        loop {
+            let output = with_retry(10, 1, 5, || {
+                self.store
+                    .list_multipart_uploads(&s3_bucket, &data_prefix, markers.clone())
+            })
+            .await?;
+
+            for upload in output.uploads {
+                let initiated = &upload.initiated;
+                let initiated_at = humantime::parse_rfc3339_weak(initiated)
+                    .map_err(|e| format!("invalid initiated time {}: {}", initiated, e))?;
+                if initiated_at > threshold {
+                    continue;
+                }
+                if let Some(callback) = &self.on_upload {
+                    callback(&upload);
+                }
+                if dry_run {
+                    continue;
+                }
+                with_retry(10, 1, 5, || {
+                    self.store
+                        .abort_multipart_upload(&s3_bucket, &upload.key, &upload.upload_id)
+                        .map(|result| match result {
+                            // the upload was completed or aborted since listed
+                            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                            result => result,
+                        })
+                })
+                .await?;
+            }
+
+            markers = match output.next_markers {
+                Some(next_markers) => Some(next_markers),
+                None => break,
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::store::{MemoryStore, ObjectAttributes};
+
+    #[tokio::test]
+    async fn aborts_only_old_uploads_under_data_prefix() {
+        let store = Arc::new(MemoryStore::new());
+        let attributes = ObjectAttributes::default();
+        for key in &["archive/data/a", "archive/manifest", "other/data/a"] {
+            let upload = store.create_multipart_upload("bucket", key, &attributes);
+            upload.await.unwrap();
+        }
+        let aborted = Arc::new(Mutex::new(Vec::new()));
+        let keys = aborted.clone();
+        let cleaner = CleanupExecutor::new(store.clone()).on_upload(Arc::new(move |upload| {
+            keys.lock().unwrap().push(upload.key.clone());
+        }));
+        let archive = ArchiveCleanup {
+            s3_bucket: "bucket".to_string(),
+            s3_prefix: "archive/".to_string(),
+            ..Default::default()
+        };
+
+        // the uploads of the running jobs are kept by default
+        cleaner.execute(archive.clone()).await.unwrap();
+        assert!(aborted.lock().unwrap().is_empty());
+
+        let archive = ArchiveCleanup {
+            older_than: Duration::from_secs(0),
+            ..archive
+        };
+        cleaner.execute(archive).await.unwrap();
+        assert_eq!(*aborted.lock().unwrap(), vec!["archive/data/a".to_string()]);
+        let uploads = store.list_multipart_uploads("bucket", "", None).await;
+        assert_eq!(uploads.unwrap().uploads.len(), 2);
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "br", "bz2", "gz", "tgz", "lz4", "lzma", "xz", "txz", "zst", "zip", "jar", "rar",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif", "mp3", "mp4", "m4a", "m4v", "mov",
+    "webm", "ogg", "flac", "parquet", "orc",
+];
+
+const TRIAL_SIZE: usize = 256 * 1024;
+
+/// The files which don't shrink below this ratio on trial are stored as they are
+const MAX_RATIO: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+impl Codec {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Codec::Zstd => "application/zstd",
+            Codec::Gzip => "application/gzip",
+        }
+    }
+
+    pub fn decompress(self, data: &[u8], target: &mut [u8]) -> Result<(), Error> {
+        let exact = match self {
+            Codec::Zstd => zstd::bulk::decompress_to_buffer(data, target)? == target.len(),
+            Codec::Gzip => {
+                let mut decoder = flate2::read::GzDecoder::new(data);
+                decoder.read_exact(target)?;
+                decoder.read(&mut [0])? == 0
+            }
+        };
+        if !exact {
+            return Err(Error::integrity(
+                "decompressed data doesn't match the size of the file",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Zstd => write!(f, "zstd"),
+            Codec::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compressor {
+    codec: Codec,
+    level: i32,
+}
+
+impl Compressor {
+    pub fn new(codec: Codec, level: Option<i32>) -> Result<Self, Error> {
+        let (levels, default) = match codec {
+            Codec::Zstd => (zstd::compression_level_range(), zstd::DEFAULT_COMPRESSION_LEVEL),
+            Codec::Gzip => (0..=9, 6),
+        };
+        let level = level.unwrap_or(default);
+        if !levels.contains(&level) {
+            return Err(format!("invalid level of {}: {}", codec, level).into());
+        }
+        Ok(Compressor { codec, level })
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.codec {
+            Codec::Zstd => Ok(zstd::bulk::compress(data, self.level)?),
+            Codec::Gzip => {
+                let level = flate2::Compression::new(self.level as u32);
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    pub fn is_worth(&self, path: &str, head: &[u8]) -> Result<bool, Error> {
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+        if let Some(extension) = extension {
+            if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+                return Ok(false);
+            }
+        }
+        if head.is_empty() {
+            return Ok(false);
+        }
+        let head = &head[..head.len().min(TRIAL_SIZE)];
+        let compressed = self.compress(head)?;
+        Ok((compressed.len() as f64) < head.len() as f64 * MAX_RATIO)
+    }
+}
+
+impl FromStr for Compressor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut split = s.splitn(2, ':');
+        let codec = match split.next().unwrap_or_default() {
+            "zstd" => Codec::Zstd,
+            "gzip" => Codec::Gzip,
+            codec => return Err(format!("unknown compression codec: {}", codec).into()),
+        };
+        let level = split
+            .next()
+            .map(|level| level.parse().map_err(|_| format!("invalid level: {}", level)))
+            .transpose()?;
+        Compressor::new(codec, level)
+    }
+}
@@ -1,28 +1,38 @@
 use std::cmp;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::mem;
+use std::ops::Deref;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use future::Either as E;
-use futures::compat::*;
 use futures::prelude::*;
 use tokio::fs;
 use tokio::prelude::*;
+use tokio::task;
 
-use rusoto_core::RusotoError;
-use rusoto_s3::{
-    CompleteMultipartUploadRequest, CompletedMultipartUpload, CompletedPart,
-    CreateMultipartUploadOutput, CreateMultipartUploadRequest, PutObjectRequest, S3Client,
-    UploadPartError, UploadPartOutput, UploadPartRequest, S3,
-};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
 
+use super::cancel::{self, Cancellation};
 use super::chan_exec;
-use super::file_entry::FileEntry;
+use super::compression::Compressor;
+use super::encryption::{self, ArchiveEncryption, DataKey, KeyProvider};
+use super::error::{Context, Error};
+use super::file_entry::{Checksum, Compression, Encryption, FileEntry, Kind, Pack};
 use super::key_resolver;
+use super::manifest;
 use super::mmap;
-use super::utils::with_retry;
-use super::Error;
+use super::progress::{Progress, ProgressCallback, Reporter};
+use super::store::{self, HeadObjectOutput, ObjectAttributes, ObjectStore};
+use super::utils::with_reported_retry;
 
-pub type PartUploadExecutor =
-    chan_exec::ChanExec<Result<UploadPartOutput, RusotoError<UploadPartError>>>;
+type PartUploadExecutor = chan_exec::ChanExec<Result<UploadedPart, Error>>;
+
+type OngoingUploads = Arc<Mutex<HashMap<String, MultipartUpload>>>;
 
 #[derive(Debug, Clone)]
 pub struct ArchiveCreate {
@@ -30,19 +40,98 @@ pub struct ArchiveCreate {
     pub part_concurrency: usize,
     pub part_size: usize,
     pub part_queue_size: usize,
+    pub put_threshold: usize,
+    /// Packs the files smaller than this into pack objects, which is disabled by zero
+    pub pack_threshold: usize,
+    pub pack_size: usize,
     pub directory: Option<PathBuf>,
     pub s3_bucket: String,
     pub s3_prefix: String,
     pub files: Vec<PathBuf>,
+    pub resume: bool,
+    pub follow_symlinks: bool,
+    pub excludes: Vec<String>,
+    pub exclude_from: Vec<PathBuf>,
+    pub includes: Vec<String>,
+    pub compressor: Option<Compressor>,
+    /// The storage class of the objects, which the manifest doesn't take if it's an archive one
+    pub storage_class: Option<String>,
+    pub tags: Vec<(String, String)>,
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Default for ArchiveCreate {
+    fn default() -> Self {
+        ArchiveCreate {
+            file_concurrency: 8,
+            part_concurrency: 8,
+            part_size: 16 * 1024 * 1024,
+            part_queue_size: 8,
+            put_threshold: 16 * 1024 * 1024,
+            pack_threshold: 0,
+            pack_size: 16 * 1024 * 1024,
+            directory: None,
+            s3_bucket: String::new(),
+            s3_prefix: String::new(),
+            files: Vec::new(),
+            resume: false,
+            follow_symlinks: false,
+            excludes: Vec::new(),
+            exclude_from: Vec::new(),
+            includes: Vec::new(),
+            compressor: None,
+            storage_class: None,
+            tags: Vec::new(),
+            metadata: Vec::new(),
+        }
+    }
 }
 
+const OPAQUE_CONTENT_TYPE: &str = "application/octet-stream";
+
+const MANIFEST_CONTENT_TYPE: &str = "application/x-ndjson";
+
+const MAX_PUT_SIZE: usize = 5 * 1024 * 1024 * 1024;
+
+const ARCHIVE_STORAGE_CLASSES: &[&str] = &["GLACIER", "DEEP_ARCHIVE"];
+
+/// The name of the files which have the patterns to exclude in gitignore syntax
+///
+/// The patterns apply to the descendants of the directory which has the file.
+/// The files themselves are never archived.
+const IGNORE_FILE_NAME: &str = ".s3arignore";
+
 pub struct CreateExecutor {
-    s3_client: S3Client,
+    store: Arc<dyn ObjectStore>,
+    reporter: Reporter,
+    cancellation: Option<Cancellation>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
 }
 
 impl CreateExecutor {
-    pub fn new(s3_client: S3Client) -> Self {
-        Self { s3_client }
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            reporter: Reporter::default(),
+            cancellation: None,
+            key_provider: None,
+        }
+    }
+
+    pub fn on_progress(mut self, callback: ProgressCallback) -> Self {
+        self.reporter = Reporter::new(callback);
+        self
+    }
+
+    /// Stops uploading when cancelled and aborts the multipart uploads unless resuming
+    pub fn with_cancellation(mut self, cancellation: Cancellation) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    pub fn with_key_provider(mut self, key_provider: Arc<dyn KeyProvider>) -> Self {
+        self.key_provider = Some(key_provider);
+        self
     }
 
     pub async fn execute(
@@ -52,102 +141,259 @@ impl CreateExecutor {
             part_concurrency,
             part_size,
             part_queue_size,
+            put_threshold,
+            pack_threshold,
+            pack_size,
             directory,
             s3_bucket,
             s3_prefix,
             files,
+            resume,
+            follow_symlinks,
+            excludes,
+            exclude_from,
+            includes,
+            compressor,
+            storage_class,
+            tags,
+            metadata,
         }: ArchiveCreate,
     ) -> Result<(), Error> {
+        if put_threshold > MAX_PUT_SIZE {
+            let message = format!("put threshold must be at most {} bytes", MAX_PUT_SIZE);
+            return Err(Error::invalid_input(message));
+        }
+        let excludes = build_gitignore(&excludes, &exclude_from)?;
+        let includes = build_gitignore(&includes, &[])?;
+        let directory = directory.unwrap_or_default();
+        let encryption = match &self.key_provider {
+            // the parts uploaded by the interrupted run were encrypted by the lost data key
+            Some(_) if resume => {
+                return Err(Error::invalid_input("encrypted uploads can't be resumed"))
+            }
+            Some(key_provider) => Some(DataKey::generate(key_provider.as_ref()).await?),
+            None => None,
+        };
+        let data_key = encryption.as_ref().map(|(data_key, _)| Arc::new(data_key.clone()));
+        let attributes = ObjectAttributes {
+            content_type: None,
+            storage_class,
+            tags,
+            metadata,
+        };
         let (part_uploader, part_upload_tasks) = chan_exec::create(part_queue_size);
+        let ongoing_uploads = OngoingUploads::default();
         let mp_uploader = MultipartUploadExecutor {
-            s3_client: self.s3_client.clone(),
+            store: self.store.clone(),
             part_uploader,
+            ongoing_uploads: ongoing_uploads.clone(),
+            directory: directory.clone(),
+            resume,
+            compressor,
+            data_key: data_key.clone(),
+            attributes: attributes.clone(),
+            reporter: self.reporter.clone(),
+        };
+        let single_uploader = SingleUploadExecutor {
+            store: self.store.clone(),
+            directory: directory.clone(),
+            resume,
+            compressor,
+            data_key: data_key.clone(),
+            attributes: attributes.clone(),
+            reporter: self.reporter.clone(),
         };
         let main = MainExecutor {
-            s3_client: self.s3_client.clone(),
+            store: self.store.clone(),
+            reporter: self.reporter.clone(),
             mp_uploader,
+            single_uploader,
             file_concurrency,
             part_size,
+            put_threshold,
+            pack_threshold,
+            pack_size,
+            compressor,
+            walker: Walker::new(directory.clone(), follow_symlinks, excludes, includes),
+            directory,
+            data_key,
+            encryption,
+            attributes,
         };
         let main_fut = async move {
             // Move main into async block and drop it after await
             // because the future won't get completed
             // if the ChanExec which main holds were not dropeed
-            main.execute(s3_bucket, s3_prefix, directory, files).await
+            main.execute(s3_bucket, s3_prefix, files).await
         };
         let part_upload_fut = part_upload_tasks
             .map(Ok)
             .try_for_each_concurrent(part_concurrency, |fut| {
                 fut.map_err(|e| format!("{:?}", e).into())
             });
-        future::try_join(main_fut, part_upload_fut)
-            .await
-            .map(|_| ())
+        let upload_fut = future::try_join(main_fut, part_upload_fut).map_ok(|_| ());
+        let result = cancel::unless_cancelled(self.cancellation.as_ref(), upload_fut).await;
+        if result.is_err() && !resume {
+            // abort the uploads of the failed and the cancelled files
+            // so that their parts won't be left in the bucket
+            let uploads: Vec<_> = ongoing_uploads.lock().unwrap().drain().collect();
+            stream::iter(uploads)
+                .for_each_concurrent(file_concurrency, |(_, mp)| {
+                    async move {
+                        // the original error is more important than the failure of aborting
+                        let _ = with_reported_retry(&self.reporter, 10, 1, 5, || {
+                            self.store.abort_multipart_upload(
+                                &mp.obj.target_bucket,
+                                &mp.obj.target_key,
+                                &mp.upload_id,
+                            )
+                        })
+                        .await;
+                    }
+                })
+                .await;
+        }
+        result
     }
 }
 
-pub struct MainExecutor {
-    s3_client: S3Client,
+struct MainExecutor {
+    store: Arc<dyn ObjectStore>,
+    reporter: Reporter,
     mp_uploader: MultipartUploadExecutor,
+    single_uploader: SingleUploadExecutor,
     file_concurrency: usize,
     part_size: usize,
+    put_threshold: usize,
+    pack_threshold: usize,
+    pack_size: usize,
+    compressor: Option<Compressor>,
+    walker: Walker,
+    directory: PathBuf,
+    data_key: Option<Arc<DataKey>>,
+    encryption: Option<(DataKey, ArchiveEncryption)>,
+    attributes: ObjectAttributes,
 }
 
 impl MainExecutor {
-    pub async fn execute(
+    async fn execute(
         &self,
         s3_bucket: String,
         s3_prefix: String,
-        directory: Option<PathBuf>,
         files: Vec<PathBuf>,
     ) -> Result<(), Error> {
-        if let Some(cwd) = directory {
-            std::env::set_current_dir(cwd).expect("failed to change current dir");
-        }
-
-        let manifest = stream::iter(files)
-            .map(read_dir_recur)
+        // packed files are uploaded again on resuming because they are cheap to upload
+        let packer = PackUploadExecutor {
+            store: self.store.clone(),
+            s3_bucket: s3_bucket.clone(),
+            s3_prefix: s3_prefix.clone(),
+            pack_size: self.pack_size,
+            directory: self.directory.clone(),
+            compressor: self.compressor,
+            data_key: self.data_key.clone(),
+            attributes: ObjectAttributes {
+                content_type: Some(OPAQUE_CONTENT_TYPE.to_string()),
+                ..self.attributes.clone()
+            },
+            reporter: self.reporter.clone(),
+            open_pack: Default::default(),
+        };
+        let packer = &packer;
+        let manifest_writer = match self.encryption.clone() {
+            Some((data_key, encryption)) => {
+                manifest::Writer::new(self.part_size).encrypted(data_key, encryption)
+            }
+            None => manifest::Writer::new(self.part_size),
+        };
+        // the files are walked ahead to count them
+        let entries: Vec<_> = stream::iter(files)
+            .map(|path| read_path_recur(path, self.walker.clone()))
             .flatten()
-            .map_err(Error::from)
+            .try_collect()
+            .await?;
+        let (files, bytes) = entries
+            .iter()
+            .filter(|entry| entry.kind() == Kind::File)
+            .fold((0, 0), |(files, bytes), entry| {
+                (files + 1, bytes + entry.size() as u64)
+            });
+        self.reporter.report(Progress::Total { files, bytes });
+        let manifest = stream::iter(entries)
+            .map(Ok::<_, Error>)
             .map_ok(|entry| {
                 async {
+                    if entry.kind() != Kind::File {
+                        return Ok(entry);
+                    }
                     let object_upload = ObjectUpload {
                         target_bucket: s3_bucket.clone(),
-                        target_key: key_resolver::data_key(&s3_prefix, entry.path()),
+                        target_key: key_resolver::data_key(
+                            &s3_prefix,
+                            entry.path(),
+                            self.data_key.as_deref(),
+                        ),
                     };
-                    self.mp_uploader
-                        .execute(self.part_size, object_upload, entry.clone())
-                        .await?;
+                    self.reporter.report(Progress::FileStarted {
+                        path: entry.path().to_string(),
+                        size: entry.size(),
+                    });
+                    let path = entry.path().to_string();
+                    let uploaded = if entry.size() < self.pack_threshold {
+                        packer.execute(entry).await
+                    } else if entry.size() <= self.put_threshold {
+                        self.single_uploader.execute(object_upload, entry).await
+                    } else {
+                        self.mp_uploader
+                            .execute(self.part_size, object_upload, entry)
+                            .await
+                    };
+                    let entry = uploaded.context(|| format!("failed to upload {}", path))?;
+                    self.reporter.report(Progress::FileCompleted {
+                        path: entry.path().to_string(),
+                    });
                     Ok(entry)
                 }
             })
             .try_buffer_unordered(self.file_concurrency)
-            .try_fold(Vec::<u8>::new(), |mut manifest, entry| {
-                manifest.extend_from_slice(format!("{}\t", entry.size()).as_bytes());
-                manifest.extend_from_slice(entry.path().as_bytes());
-                manifest.push(b'\n');
-                async move { Ok(manifest) }
+            .try_fold(manifest_writer, |mut manifest, entry| {
+                let result = manifest.add(&entry).map(|_| manifest);
+                async move { result }
             })
-            .await?;
+            .await?
+            .finish()?;
+        packer.finish().await?;
 
-        let put_object_request = PutObjectRequest {
-            bucket: s3_bucket.clone(),
-            key: key_resolver::manifest_key(&s3_prefix),
-            body: Some(manifest.into()),
-            ..Default::default()
+        let manifest_key = key_resolver::manifest_key(&s3_prefix);
+        // the manifest is kept readable to list and restore the archive
+        let storage_class = self
+            .attributes
+            .storage_class
+            .clone()
+            .filter(|class| !ARCHIVE_STORAGE_CLASSES.contains(&class.as_str()));
+        let attributes = ObjectAttributes {
+            content_type: Some(MANIFEST_CONTENT_TYPE.to_string()),
+            storage_class,
+            ..self.attributes.clone()
         };
-        self.s3_client
-            .put_object(put_object_request)
-            .compat()
-            .await?;
+        self.store
+            .put_object(&s3_bucket, &manifest_key, manifest, &attributes)
+            .await
+            .context(|| format!("failed to upload manifest {}", manifest_key))?;
         Ok(())
     }
 }
 
 #[derive(Clone)]
-pub struct MultipartUploadExecutor {
-    s3_client: S3Client,
+struct MultipartUploadExecutor {
+    store: Arc<dyn ObjectStore>,
     part_uploader: PartUploadExecutor,
+    ongoing_uploads: OngoingUploads,
+    directory: PathBuf,
+    resume: bool,
+    compressor: Option<Compressor>,
+    data_key: Option<Arc<DataKey>>,
+    attributes: ObjectAttributes,
+    reporter: Reporter,
 }
 
 impl MultipartUploadExecutor {
@@ -156,152 +402,726 @@ impl MultipartUploadExecutor {
         part_size: usize,
         object_upload: ObjectUpload,
         source: FileEntry,
-    ) -> Result<(), Error> {
-        let body = unsafe { source.open() }.await?;
-        let mp_start = MultipartUploadStart::new(object_upload);
-        let CreateMultipartUploadOutput { upload_id, .. } = with_retry(10, 1, 5, || {
-            self.s3_client
-                .create_multipart_upload(mp_start.start())
-                .compat()
-        })
-        .await?;
-        let mp = mp_start.started(upload_id.expect("no upload_id in response"));
-        let part_bodies = MultipartUpload::parts(part_size, body);
-        let part_bodies_with_number = part_bodies.enumerate().map(|(i, b)| (i as i64 + 1, b));
-        let mut completed_parts: Vec<_> = stream::iter(part_bodies_with_number)
+    ) -> Result<FileEntry, Error> {
+        let body = unsafe { source.open(&self.directory) }.await?;
+        let part_bodies: Vec<_> = MultipartUpload::parts(part_size, body).collect();
+        // each part is compressed into a frame of the compression
+        let compressor = match (self.compressor, part_bodies.first()) {
+            (Some(compressor), Some(head)) if compressor.is_worth(source.path(), head)? => {
+                Some(compressor)
+            }
+            _ => None,
+        };
+        let compression = |frames| {
+            compressor.map(|compressor| Compression {
+                codec: compressor.codec(),
+                frame_size: part_size,
+                frames,
+            })
+        };
+        let (mp, uploaded_parts) = match self.resumable_upload(&object_upload).await? {
+            Some(upload_id) => {
+                let mp = MultipartUpload::new(object_upload, upload_id);
+                let uploaded_parts = self.uploaded_parts(&mp).await?;
+                (mp, uploaded_parts)
+            }
+            None => {
+                let uploaded = if self.resume {
+                    self.uploaded_object(&object_upload, &source, &part_bodies, compressor)
+                        .await?
+                } else {
+                    None
+                };
+                if let Some(object_part_sizes) = uploaded {
+                    let part_checksums = part_bodies
+                        .iter()
+                        .map(|body| (crc32c::crc32c(body), body.len()))
+                        .collect();
+                    let entry = source.with_checksum(Checksum::new(part_checksums));
+                    return Ok(encoded(entry, compression(object_part_sizes), None));
+                }
+                let attributes = ObjectAttributes {
+                    content_type: Some(content_type(
+                        source.path(),
+                        compressor,
+                        self.data_key.is_some(),
+                    )),
+                    ..self.attributes.clone()
+                };
+                let upload_id = with_reported_retry(&self.reporter, 10, 1, 5, || {
+                    self.store.create_multipart_upload(
+                        &object_upload.target_bucket,
+                        &object_upload.target_key,
+                        &attributes,
+                    )
+                })
+                .await?;
+                let mp = MultipartUpload::new(object_upload, upload_id);
+                (mp, HashMap::new())
+            }
+        };
+        self.ongoing_uploads
+            .lock()
+            .unwrap()
+            .insert(mp.upload_id.clone(), mp.clone());
+        let part_bodies_with_number = part_bodies
+            .into_iter()
+            .enumerate()
+            .map(|(i, b)| (i as i64 + 1, b));
+        let uploaded_parts = &uploaded_parts;
+        let parts = stream::iter(part_bodies_with_number);
+        let mut completed_parts: Vec<(i64, UploadedPart, _)> = parts
             .map(Ok::<_, Error>)
             .map_ok(|(part_number, part_body)| {
                 let mut exec = self.part_uploader.clone();
-                let s3_client = self.s3_client.clone();
+                let store = self.store.clone();
                 let mp = mp.clone();
+                let uploaded_part = uploaded_parts.get(&part_number).cloned();
+                let part_checksum = (crc32c::crc32c(&part_body), part_body.len());
+                let data_key = self.data_key.clone();
+                let encodes = compressor.is_some() || data_key.is_some();
+                let reporter = self.reporter.clone();
+                let path = source.path().to_string();
                 async move {
-                    let UploadPartOutput { e_tag, .. } = exec
+                    let uploaded = exec
                         .execute(
-                            with_retry(10, 1, 5, move || {
-                                let req = mp.upload_part(part_number, &part_body);
-                                s3_client.upload_part(req).compat()
-                            })
+                            async move {
+                                let started = Instant::now();
+                                let size = part_body.len();
+                                let (part_body, nonce) = if encodes {
+                                    let aad = encryption::part_aad(&path, part_number);
+                                    let (body, nonce) =
+                                        encode(part_body, compressor, data_key, aad).await?;
+                                    (PartBody::Encoded(body), nonce)
+                                } else {
+                                    (PartBody::Raw(part_body), None)
+                                };
+                                let object_part_size = part_body.len();
+                                // skip parts which were uploaded by the interrupted run
+                                let uploaded_part = uploaded_part.filter(|p| p.matches(&part_body));
+                                if let Some(part) = uploaded_part {
+                                    return Ok(part);
+                                }
+                                let e_tag = with_reported_retry(&reporter, 10, 1, 5, move || {
+                                    store.upload_part(
+                                        &mp.obj.target_bucket,
+                                        &mp.obj.target_key,
+                                        &mp.upload_id,
+                                        part_number,
+                                        part_body.to_vec(),
+                                    )
+                                })
+                                .await?;
+                                reporter.report(Progress::PartCompleted {
+                                    path,
+                                    part_number,
+                                    size,
+                                    elapsed: started.elapsed(),
+                                });
+                                Ok(UploadedPart {
+                                    e_tag,
+                                    size: object_part_size,
+                                    nonce,
+                                })
+                            }
                             .boxed(),
                         )
                         .await??;
-                    let part_number = Some(part_number);
-                    Ok(CompletedPart { e_tag, part_number })
+                    Ok((part_number, uploaded, part_checksum))
                 }
             })
             .try_buffer_unordered(8)
             .try_collect()
             .await?;
 
-        completed_parts.sort_by_key(|part| part.part_number);
+        completed_parts.sort_by_key(|(part_number, _, _)| *part_number);
+        let part_checksums = completed_parts.iter().map(|(_, _, checksum)| *checksum);
+        let part_checksums = part_checksums.collect();
+        let object_part_sizes = completed_parts.iter().map(|(_, part, _)| part.size);
+        let object_part_sizes = object_part_sizes.collect();
+        let nonces = completed_parts.iter().map(|(_, part, _)| part.nonce.clone());
+        let nonces = nonces.collect();
+        let completed_parts: Vec<_> = completed_parts
+            .into_iter()
+            .map(|(part_number, part, _)| (part_number, part.e_tag))
+            .collect();
 
-        with_retry(10, 1, 5, move || {
-            self.s3_client
-                .complete_multipart_upload(mp.complete((&completed_parts).clone()))
-                .compat()
+        with_reported_retry(&self.reporter, 10, 1, 5, || {
+            self.store.complete_multipart_upload(
+                &mp.obj.target_bucket,
+                &mp.obj.target_key,
+                &mp.upload_id,
+                completed_parts.clone(),
+            )
         })
         .await?;
-        Ok(())
+        self.ongoing_uploads.lock().unwrap().remove(&mp.upload_id);
+        let entry = source.with_checksum(Checksum::new(part_checksums));
+        Ok(encoded(entry, compression(object_part_sizes), nonces))
     }
-}
 
-pub fn read_dir_recur(dir: PathBuf) -> stream::BoxStream<'static, io::Result<FileEntry>> {
-    fs::read_dir(dir)
-        .try_flatten_stream()
-        .and_then(|entry| {
-            async move {
-                let metadata = entry.metadata().await?;
-                let path = entry.path();
-                if metadata.is_dir() {
-                    return Ok(E::Left(E::Left(read_dir_recur(path))));
-                } else if metadata.is_file() {
-                    let size = metadata.len() as usize;
-                    return Ok(E::Left(E::Right(stream::once(async move {
-                        // do panic simply if file path contains non-UTF-8 strings
-                        // because it's very rare and it doesn't have to be care
-                        let path_string = path
-                            .to_str()
-                            .expect("non-UTF-8 strings in path")
-                            .to_string();
-                        Ok(FileEntry::new(path_string, size))
-                    }))));
-                }
-                Ok(E::Right(stream::empty()))
+    async fn resumable_upload(&self, obj: &ObjectUpload) -> Result<Option<String>, Error> {
+        if !self.resume {
+            return Ok(None);
+        }
+        let mut latest: Option<(String, String)> = None;
+        let mut markers = None;
+        loop {
+            let output = with_reported_retry(&self.reporter, 10, 1, 5, || {
+                self.store.list_multipart_uploads(
+                    &obj.target_bucket,
+                    &obj.target_key,
+                    markers.clone(),
+                )
+            })
+            .await?;
+            let uploads = output
+                .uploads
+                .into_iter()
+                .filter(|u| u.key == obj.target_key)
+                .map(|u| (u.initiated, u.upload_id));
+            // initiated timestamps are ISO 8601 so that they can be compared as strings
+            latest = uploads.chain(latest).max();
+            markers = match output.next_markers {
+                Some(next_markers) => Some(next_markers),
+                None => break,
+            };
+        }
+        Ok(latest.map(|(_, upload_id)| upload_id))
+    }
+
+    async fn uploaded_parts(
+        &self,
+        mp: &MultipartUpload,
+    ) -> Result<HashMap<i64, UploadedPart>, Error> {
+        let mut uploaded_parts = HashMap::new();
+        let mut marker = None;
+        loop {
+            let output = with_reported_retry(&self.reporter, 10, 1, 5, || {
+                self.store.list_parts(
+                    &mp.obj.target_bucket,
+                    &mp.obj.target_key,
+                    &mp.upload_id,
+                    marker,
+                )
+            })
+            .await?;
+            for part in output.parts {
+                let (part_number, e_tag, size) = (part.part_number, part.e_tag, part.size);
+                let part = UploadedPart {
+                    e_tag,
+                    size,
+                    nonce: None,
+                };
+                uploaded_parts.insert(part_number, part);
             }
+            marker = match output.next_marker {
+                Some(next_marker) => Some(next_marker),
+                None => break,
+            };
+        }
+        Ok(uploaded_parts)
+    }
+
+    async fn uploaded_object(
+        &self,
+        obj: &ObjectUpload,
+        source: &FileEntry,
+        part_bodies: &[mmap::Chunk],
+        compressor: Option<Compressor>,
+    ) -> Result<Option<Vec<usize>>, Error> {
+        let head = with_reported_retry(&self.reporter, 10, 1, 5, || {
+            self.store.head_object(&obj.target_bucket, &obj.target_key)
         })
-        .try_flatten()
-        .boxed()
+        .await?;
+        let (content_length, e_tag) = match head {
+            Some(HeadObjectOutput {
+                content_length,
+                e_tag: Some(e_tag),
+                ..
+            }) => (content_length, e_tag),
+            _ => return Ok(None),
+        };
+        if compressor.is_none() && content_length != source.size() {
+            return Ok(None);
+        }
+        let mut digests = Vec::with_capacity(part_bodies.len());
+        let mut part_sizes = Vec::with_capacity(part_bodies.len());
+        for body in part_bodies {
+            let (digest, size) = match compressor {
+                Some(compressor) => {
+                    let compressed = compressor.compress(body)?;
+                    (md5::compute(&compressed), compressed.len())
+                }
+                None => (md5::compute(&body[..]), body.len()),
+            };
+            digests.push(digest);
+            part_sizes.push(size);
+        }
+        let uploaded = content_length == part_sizes.iter().sum::<usize>()
+            && e_tag == store::multipart_e_tag(&digests);
+        Ok(Some(part_sizes).filter(|_| uploaded))
+    }
+}
+
+/// Uploads small files by PutObject, which needs a third of the requests of a multipart upload
+struct SingleUploadExecutor {
+    store: Arc<dyn ObjectStore>,
+    directory: PathBuf,
+    resume: bool,
+    compressor: Option<Compressor>,
+    data_key: Option<Arc<DataKey>>,
+    attributes: ObjectAttributes,
+    reporter: Reporter,
+}
+
+impl SingleUploadExecutor {
+    async fn execute(
+        &self,
+        object_upload: ObjectUpload,
+        source: FileEntry,
+    ) -> Result<FileEntry, Error> {
+        let started = Instant::now();
+        let body = fs::read(self.directory.join(source.path())).await?;
+        if body.len() != source.size() {
+            return Err(format!("{} was changed while archiving", source.path()).into());
+        }
+        let size = body.len();
+        let checksum = Checksum::new(vec![(crc32c::crc32c(&body), size)]);
+        // the whole file is compressed into a single frame
+        let compressor = match self.compressor {
+            Some(compressor) if compressor.is_worth(source.path(), &body)? => Some(compressor),
+            _ => None,
+        };
+        let (body, nonce) = if compressor.is_some() || self.data_key.is_some() {
+            let aad = encryption::part_aad(source.path(), 1);
+            encode(body, compressor, self.data_key.clone(), aad).await?
+        } else {
+            (body, None)
+        };
+        let compression = compressor.map(|compressor| Compression {
+            codec: compressor.codec(),
+            frame_size: size,
+            frames: vec![body.len()],
+        });
+        let entry = encoded(source.with_checksum(checksum), compression, nonce.map(|n| vec![n]));
+        if self.resume && self.is_uploaded(&object_upload, &body).await? {
+            return Ok(entry);
+        }
+        let ObjectUpload {
+            target_bucket,
+            target_key,
+        } = &object_upload;
+        let attributes = ObjectAttributes {
+            content_type: Some(content_type(
+                entry.path(),
+                compressor,
+                self.data_key.is_some(),
+            )),
+            ..self.attributes.clone()
+        };
+        with_reported_retry(&self.reporter, 10, 1, 5, || {
+            self.store
+                .put_object(target_bucket, target_key, body.clone(), &attributes)
+        })
+        .await?;
+        self.reporter.report(Progress::PartCompleted {
+            path: entry.path().to_string(),
+            part_number: 1,
+            size,
+            elapsed: started.elapsed(),
+        });
+        Ok(entry)
+    }
+
+    async fn is_uploaded(&self, obj: &ObjectUpload, body: &[u8]) -> Result<bool, Error> {
+        let head = with_reported_retry(&self.reporter, 10, 1, 5, || {
+            self.store.head_object(&obj.target_bucket, &obj.target_key)
+        })
+        .await?;
+        Ok(match head {
+            Some(HeadObjectOutput {
+                content_length,
+                e_tag: Some(e_tag),
+                ..
+            }) => content_length == body.len() && e_tag == store::e_tag(body),
+            _ => false,
+        })
+    }
+}
+
+struct PackUploadExecutor {
+    store: Arc<dyn ObjectStore>,
+    s3_bucket: String,
+    s3_prefix: String,
+    pack_size: usize,
+    directory: PathBuf,
+    compressor: Option<Compressor>,
+    data_key: Option<Arc<DataKey>>,
+    attributes: ObjectAttributes,
+    reporter: Reporter,
+    open_pack: Mutex<OpenPack>,
+}
+
+#[derive(Debug, Default)]
+struct OpenPack {
+    index: usize,
+    content: Vec<u8>,
+    files_count: usize,
+}
+
+impl PackUploadExecutor {
+    async fn execute(&self, source: FileEntry) -> Result<FileEntry, Error> {
+        let started = Instant::now();
+        let body = fs::read(self.directory.join(source.path())).await?;
+        if body.len() != source.size() {
+            return Err(format!("{} was changed while archiving", source.path()).into());
+        }
+        let size = body.len();
+        let checksum = Checksum::new(vec![(crc32c::crc32c(&body), size)]);
+        // each packed file is compressed and encrypted by itself to be extracted alone
+        let compressor = match self.compressor {
+            Some(compressor) if compressor.is_worth(source.path(), &body)? => Some(compressor),
+            _ => None,
+        };
+        let (body, nonce) = if compressor.is_some() || self.data_key.is_some() {
+            let aad = encryption::part_aad(source.path(), 1);
+            encode(body, compressor, self.data_key.clone(), aad).await?
+        } else {
+            (body, None)
+        };
+        let compression = compressor.map(|compressor| Compression {
+            codec: compressor.codec(),
+            frame_size: size,
+            frames: vec![body.len()],
+        });
+        let (pack, full_pack) = {
+            let mut open_pack = self.open_pack.lock().unwrap();
+            let pack = Pack {
+                key: key_resolver::pack_name(open_pack.index),
+                offset: open_pack.content.len(),
+                len: body.len(),
+            };
+            open_pack.content.extend_from_slice(&body);
+            open_pack.files_count += 1;
+            let full_pack = if open_pack.content.len() >= self.pack_size {
+                let index = open_pack.index + 1;
+                Some(mem::replace(
+                    &mut *open_pack,
+                    OpenPack {
+                        index,
+                        ..Default::default()
+                    },
+                ))
+            } else {
+                None
+            };
+            (pack, full_pack)
+        };
+        self.reporter.report(Progress::PartCompleted {
+            path: source.path().to_string(),
+            part_number: 1,
+            size,
+            elapsed: started.elapsed(),
+        });
+        if let Some(full_pack) = full_pack {
+            self.upload(full_pack).await?;
+        }
+        let entry = source.with_checksum(checksum).packed_in(pack);
+        Ok(encoded(entry, compression, nonce.map(|nonce| vec![nonce])))
+    }
+
+    async fn finish(&self) -> Result<(), Error> {
+        let last_pack = mem::take(&mut *self.open_pack.lock().unwrap());
+        if last_pack.files_count == 0 {
+            return Ok(());
+        }
+        self.upload(last_pack).await
+    }
+
+    async fn upload(&self, pack: OpenPack) -> Result<(), Error> {
+        let key = key_resolver::pack_key(&self.s3_prefix, &key_resolver::pack_name(pack.index));
+        with_reported_retry(&self.reporter, 10, 1, 5, || {
+            self.store.put_object(
+                &self.s3_bucket,
+                &key,
+                pack.content.clone(),
+                &self.attributes,
+            )
+        })
+        .await
+        .context(|| format!("failed to upload pack {}", key))
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct ObjectUpload {
-    target_bucket: String,
-    target_key: String,
+struct UploadedPart {
+    e_tag: String,
+    size: usize,
+    nonce: Option<String>,
 }
 
-pub struct MultipartUploadStart {
-    obj: ObjectUpload,
+impl UploadedPart {
+    fn matches(&self, body: &[u8]) -> bool {
+        self.size == body.len() && self.e_tag == store::e_tag(body)
+    }
 }
 
-impl MultipartUploadStart {
-    pub fn new(obj: ObjectUpload) -> MultipartUploadStart {
-        MultipartUploadStart { obj }
+enum PartBody {
+    Raw(mmap::Chunk),
+    Encoded(Vec<u8>),
+}
+
+impl Deref for PartBody {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        match self {
+            PartBody::Raw(chunk) => chunk,
+            PartBody::Encoded(body) => body,
+        }
     }
+}
 
-    pub fn start(&self) -> CreateMultipartUploadRequest {
-        CreateMultipartUploadRequest {
-            bucket: self.obj.target_bucket.clone(),
-            key: self.obj.target_key.clone(),
-            ..Default::default()
+async fn encode<B>(
+    body: B,
+    compressor: Option<Compressor>,
+    data_key: Option<Arc<DataKey>>,
+    aad: Vec<u8>,
+) -> Result<(Vec<u8>, Option<String>), Error>
+where
+    B: Deref<Target = [u8]> + Send + 'static,
+{
+    task::spawn_blocking(move || {
+        let body = match compressor {
+            Some(compressor) => compressor.compress(&body)?,
+            None => body.to_vec(),
+        };
+        match data_key {
+            Some(data_key) => {
+                let (sealed, nonce) = data_key.seal(body, &aad)?;
+                Ok((sealed, Some(nonce)))
+            }
+            None => Ok((body, None)),
         }
+    })
+    .await?
+}
+
+fn content_type(path: &str, compressor: Option<Compressor>, encrypted: bool) -> String {
+    match compressor {
+        _ if encrypted => OPAQUE_CONTENT_TYPE.to_string(),
+        Some(compressor) => compressor.codec().content_type().to_string(),
+        None => mime_guess::from_path(path)
+            .first_or_octet_stream()
+            .to_string(),
     }
+}
+
+fn encoded(
+    entry: FileEntry,
+    compression: Option<Compression>,
+    nonces: Option<Vec<String>>,
+) -> FileEntry {
+    let entry = match compression {
+        Some(compression) => entry.compressed_with(compression),
+        None => entry,
+    };
+    match nonces {
+        Some(nonces) => entry.encrypted_with(Encryption { nonces }),
+        None => entry,
+    }
+}
 
-    pub fn started(self, upload_id: String) -> MultipartUpload {
-        MultipartUpload {
-            obj: self.obj,
-            upload_id,
+fn build_gitignore(patterns: &[String], pattern_files: &[PathBuf]) -> Result<Gitignore, Error> {
+    let mut builder = GitignoreBuilder::new(".");
+    for pattern in patterns {
+        builder.add_line(None, pattern).map_err(|e| e.to_string())?;
+    }
+    for pattern_file in pattern_files {
+        if let Some(e) = builder.add(pattern_file) {
+            return Err(e.to_string().into());
         }
     }
+    let gitignore = builder.build().map_err(|e| e.to_string())?;
+    Ok(gitignore)
+}
+
+#[derive(Debug, Clone)]
+struct Walker {
+    /// The directory which the paths are relative to
+    root: PathBuf,
+    follow_symlinks: bool,
+    inodes: Arc<Mutex<HashMap<(u64, u64), String>>>,
+    /// The directories from the root to the current one to detect loops of symlinks
+    ancestors: Vec<(u64, u64)>,
+    excludes: Arc<Gitignore>,
+    ignores: Vec<Arc<Gitignore>>,
+    includes: Arc<Gitignore>,
+}
+
+impl Walker {
+    fn new(root: PathBuf, follow_symlinks: bool, excludes: Gitignore, includes: Gitignore) -> Self {
+        Walker {
+            root,
+            follow_symlinks,
+            inodes: Default::default(),
+            ancestors: Vec::new(),
+            excludes: Arc::new(excludes),
+            ignores: Vec::new(),
+            includes: Arc::new(includes),
+        }
+    }
+
+    async fn enter_dir(&mut self, dir: &Path, inode: (u64, u64)) -> io::Result<()> {
+        self.ancestors.push(inode);
+        let ignore_file = self.root.join(dir).join(IGNORE_FILE_NAME);
+        if fs::symlink_metadata(&ignore_file).await.is_err() {
+            return Ok(());
+        }
+        let mut builder = GitignoreBuilder::new(dir.join(""));
+        let to_io_error = |e: ignore::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+        if let Some(e) = builder.add(&ignore_file) {
+            return Err(to_io_error(e));
+        }
+        let ignore = builder.build().map_err(to_io_error)?;
+        self.ignores.push(Arc::new(ignore));
+        Ok(())
+    }
+
+    /// Directories are never excluded by the include patterns
+    /// so that the files in them can be included.
+    fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if !is_dir && path.file_name() == Some(OsStr::new(IGNORE_FILE_NAME)) {
+            return true;
+        }
+        if self.excludes.matched(path, is_dir).is_ignore() {
+            return true;
+        }
+        // the deepest ignore file which has the matching pattern decides
+        for ignore in self.ignores.iter().rev() {
+            match ignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => break,
+                Match::None => {}
+            }
+        }
+        !is_dir && !self.includes.is_empty() && !self.includes.matched(path, false).is_ignore()
+    }
+
+    async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let path = &self.root.join(path);
+        if !self.follow_symlinks {
+            return fs::symlink_metadata(path).await;
+        }
+        match fs::metadata(path).await {
+            // archive the broken symlink as it is
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => fs::symlink_metadata(path).await,
+            result => result,
+        }
+    }
+
+    fn hard_link_target(&self, path: &str, metadata: &Metadata) -> Option<String> {
+        if metadata.is_dir() || metadata.nlink() < 2 {
+            return None;
+        }
+        let mut inodes = self.inodes.lock().unwrap();
+        let first = inodes
+            .entry((metadata.dev(), metadata.ino()))
+            .or_insert_with(|| path.to_string());
+        Some(first.clone()).filter(|first| first != path)
+    }
+}
+
+fn read_path_recur(
+    path: PathBuf,
+    walker: Walker,
+) -> stream::BoxStream<'static, Result<FileEntry, Error>> {
+    let context = format!("failed to read {}", path.display());
+    async move {
+        let metadata = walker.metadata(&path).await?;
+        let path_string = path
+            .to_str()
+            .ok_or_else(|| Error::invalid_input(format!("non-UTF-8 path: {}", path.display())))?
+            .to_string();
+        let file_type = metadata.file_type();
+        if walker.is_excluded(&path, file_type.is_dir()) {
+            // excluded directories are not descended into
+            return Ok(stream::empty().boxed());
+        }
+        let inode = (metadata.dev(), metadata.ino());
+        let mut entry = FileEntry::with_metadata(path_string.clone(), &metadata);
+        if file_type.is_dir() && walker.ancestors.contains(&inode) {
+            // the followed symlink loops, so it's archived as the symlink
+            let metadata = fs::symlink_metadata(walker.root.join(&path)).await?;
+            entry = FileEntry::with_metadata(path_string, &metadata);
+        } else if file_type.is_dir() {
+            let mut walker = walker.clone();
+            walker.enter_dir(&path, inode).await?;
+            let children = read_dir_recur(path, walker);
+            return Ok(stream::once(future::ok(entry)).chain(children).boxed());
+        } else if let Some(first) = walker.hard_link_target(&path_string, &metadata) {
+            entry = entry.hard_link_to(first);
+        } else if !file_type.is_file() && !file_type.is_symlink() {
+            // sockets, FIFOs and devices are not archived
+            return Ok(stream::empty().boxed());
+        }
+        if entry.kind() == Kind::Symlink {
+            let target = fs::read_link(walker.root.join(&path)).await?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| {
+                    Error::invalid_input(format!("non-UTF-8 path: {}", target.display()))
+                })?
+                .to_string();
+            entry = entry.symlink_to(target);
+        }
+        Ok(stream::once(future::ok(entry)).boxed())
+    }
+    .map_err(|e: Error| e.context(context))
+    .try_flatten_stream()
+    .boxed()
+}
+
+fn read_dir_recur(
+    dir: PathBuf,
+    walker: Walker,
+) -> stream::BoxStream<'static, Result<FileEntry, Error>> {
+    let context = format!("failed to read {}", dir.display());
+    fs::read_dir(walker.root.join(&dir))
+        .try_flatten_stream()
+        .map_err(move |e| Error::from(e).context(context.clone()))
+        .map_ok(move |entry| read_path_recur(dir.join(entry.file_name()), walker.clone()))
+        .try_flatten()
+        .boxed()
+}
+
+#[derive(Debug, Clone)]
+struct ObjectUpload {
+    target_bucket: String,
+    target_key: String,
 }
 
 #[derive(Clone)]
-pub struct MultipartUpload {
+struct MultipartUpload {
     obj: ObjectUpload,
     upload_id: String,
 }
 
 impl MultipartUpload {
-    pub fn parts(part_size: usize, mmap_handle: mmap::Handle) -> PartUploadBodies {
+    fn new(obj: ObjectUpload, upload_id: String) -> MultipartUpload {
+        MultipartUpload { obj, upload_id }
+    }
+
+    fn parts(part_size: usize, mmap_handle: mmap::Handle) -> PartUploadBodies {
         let mmap_chunker = Some(mmap::Chunker::new(mmap_handle));
         PartUploadBodies {
             part_size,
             mmap_chunker,
         }
     }
-
-    pub fn upload_part(&self, part_number: i64, body: &mmap::Chunk) -> UploadPartRequest {
-        UploadPartRequest {
-            body: Some(body.to_vec().into()),
-            bucket: self.obj.target_bucket.clone(),
-            key: self.obj.target_key.clone(),
-            content_length: Some(body.len() as i64),
-            part_number,
-            upload_id: self.upload_id.clone(),
-            ..Default::default()
-        }
-    }
-
-    pub fn complete(&self, parts: Vec<CompletedPart>) -> CompleteMultipartUploadRequest {
-        CompleteMultipartUploadRequest {
-            bucket: self.obj.target_bucket.clone(),
-            key: self.obj.target_key.clone(),
-            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
-            upload_id: self.upload_id.clone(),
-            ..Default::default()
-        }
-    }
 }
 
-pub struct PartUploadBodies {
+struct PartUploadBodies {
     part_size: usize,
     mmap_chunker: Option<mmap::Chunker>,
 }
@@ -323,3 +1143,311 @@ impl Iterator for PartUploadBodies {
         Some(mmap_chunk)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::extract::{ArchiveExtract, ExtractExecutor};
+    use crate::store::{LocalStore, MemoryStore, ObjectRange};
+    use crate::testing::*;
+    use crate::utils::test_dir;
+    use crate::Codec;
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn patterns(patterns: &[&str]) -> Gitignore {
+        let patterns: Vec<_> = patterns.iter().map(|p| p.to_string()).collect();
+        build_gitignore(&patterns, &[]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn layers_ignore_files() {
+        let dir = test_dir("layers_ignore_files");
+        write(&dir.join(IGNORE_FILE_NAME), "*.log\n*.tmp\nbuild/\n");
+        write(&dir.join("sub").join(IGNORE_FILE_NAME), "!*.tmp\n");
+
+        let mut walker = Walker::new(PathBuf::new(), false, patterns(&[]), patterns(&[]));
+        walker.enter_dir(&dir, (0, 0)).await.unwrap();
+        assert!(walker.is_excluded(&dir.join("a.log"), false));
+        assert!(!walker.is_excluded(&dir.join("a.txt"), false));
+        assert!(walker.is_excluded(&dir.join("build"), true));
+        assert!(!walker.is_excluded(&dir.join("build"), false));
+        assert!(walker.is_excluded(&dir.join(IGNORE_FILE_NAME), false));
+
+        let mut sub_walker = walker.clone();
+        sub_walker
+            .enter_dir(&dir.join("sub"), (0, 1))
+            .await
+            .unwrap();
+        // the deeper ignore file overrides the shallower one
+        assert!(!sub_walker.is_excluded(&dir.join("sub/a.tmp"), false));
+        assert!(sub_walker.is_excluded(&dir.join("sub/a.log"), false));
+        // the ignore file only applies to the descendants of its directory
+        let mut other_walker = walker.clone();
+        other_walker
+            .enter_dir(&dir.join("other"), (0, 2))
+            .await
+            .unwrap();
+        assert!(other_walker.is_excluded(&dir.join("other/a.tmp"), false));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn excludes_take_precedence_over_ignore_files() {
+        let dir = test_dir("excludes_take_precedence");
+        write(&dir.join(IGNORE_FILE_NAME), "!*.tmp\n");
+
+        let mut walker = Walker::new(PathBuf::new(), false, patterns(&["*.tmp"]), patterns(&[]));
+        walker.enter_dir(&dir, (0, 0)).await.unwrap();
+        assert!(walker.is_excluded(&dir.join("a.tmp"), false));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn includes_never_exclude_directories() {
+        let walker = Walker::new(PathBuf::new(), false, patterns(&[]), patterns(&["*.txt"]));
+        assert!(!walker.is_excluded(Path::new("a"), true));
+        assert!(!walker.is_excluded(Path::new("a/b.txt"), false));
+        assert!(walker.is_excluded(Path::new("a/b.bin"), false));
+    }
+
+    #[tokio::test]
+    async fn walks_without_excluded_files() {
+        let dir = test_dir("walks_without_excluded_files");
+        write(&dir.join(IGNORE_FILE_NAME), "build/\n*.log\n");
+        write(&dir.join("a.txt"), "a");
+        write(&dir.join("a.log"), "a");
+        write(&dir.join("build/b.txt"), "b");
+        write(&dir.join("sub").join(IGNORE_FILE_NAME), "!keep.log\n");
+        write(&dir.join("sub/keep.log"), "c");
+        write(&dir.join("sub/drop.log"), "c");
+
+        // the paths are relative to the root
+        let root = dir.parent().unwrap().to_path_buf();
+        let name = PathBuf::from(dir.file_name().unwrap());
+        let walker = Walker::new(root, false, patterns(&[]), patterns(&[]));
+        let entries: Vec<_> = read_path_recur(name.clone(), walker)
+            .try_collect()
+            .await
+            .unwrap();
+        let mut paths: Vec<_> = entries
+            .iter()
+            .map(|entry| Path::new(entry.path()).strip_prefix(&name).unwrap())
+            .map(|path| path.to_str().unwrap().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["", "a.txt", "sub", "sub/keep.log"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_non_utf8_paths() {
+        let dir = test_dir("rejects_non_utf8_paths");
+        write(&dir.join(OsStr::from_bytes(b"\xff")), "a");
+
+        let walker = Walker::new(PathBuf::new(), false, patterns(&[]), patterns(&[]));
+        let result: Result<Vec<_>, _> = read_path_recur(dir.clone(), walker).try_collect().await;
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(e.chain().iter().any(|m| m.starts_with("non-UTF-8 path")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn round_trips_by_multipart_uploads() {
+        let store = Arc::new(MemoryStore::new());
+        run(round_trip("multipart", store.clone(), |a| a, None));
+        run(async {
+            let key = key_resolver::data_key(PREFIX, RANDOM_PATH, None);
+            let output = store.get_object(BUCKET, &key, ObjectRange::Part(1)).await;
+            assert_eq!(output.unwrap().parts_count, Some(5));
+        });
+    }
+
+    #[test]
+    fn round_trips_by_single_puts() {
+        let store = Arc::new(MemoryStore::new());
+        let archive = |a| ArchiveCreate {
+            put_threshold: 1024 * 1024,
+            ..a
+        };
+        run(round_trip("single_put", store.clone(), archive, None));
+        run(async {
+            // the object without parts is got by the ranges
+            let key = key_resolver::data_key(PREFIX, RANDOM_PATH, None);
+            let output = store.get_object(BUCKET, &key, ObjectRange::Part(1)).await;
+            assert_eq!(output.unwrap().parts_count, None);
+        });
+    }
+
+    #[test]
+    fn round_trips_packs() {
+        let store = Arc::new(MemoryStore::new());
+        let archive = |a| ArchiveCreate {
+            pack_threshold: 100,
+            pack_size: 1024,
+            ..a
+        };
+        run(round_trip("packs", store.clone(), archive, None));
+        run(async {
+            let pack_key = key_resolver::pack_key(PREFIX, &key_resolver::pack_name(0));
+            let pack = store.head_object(BUCKET, &pack_key).await.unwrap();
+            assert!(pack.is_some());
+            let key = key_resolver::data_key(PREFIX, "small.txt", None);
+            assert!(store.head_object(BUCKET, &key).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn round_trips_compressed() {
+        for codec in &[Codec::Zstd, Codec::Gzip] {
+            let store = Arc::new(MemoryStore::new());
+            let archive = |a| ArchiveCreate {
+                compressor: Some(Compressor::new(*codec, None).unwrap()),
+                ..a
+            };
+            let name = format!("compressed-{}", codec);
+            run(round_trip(&name, store.clone(), archive, None));
+            run(async {
+                let key = key_resolver::data_key(PREFIX, "dir/text.txt", None);
+                assert!(content_length(store.as_ref(), &key).await < 5000);
+                let key = key_resolver::data_key(PREFIX, RANDOM_PATH, None);
+                assert_eq!(content_length(store.as_ref(), &key).await, 5000);
+            });
+        }
+    }
+
+    #[test]
+    fn round_trips_compressed_packs() {
+        let store = Arc::new(MemoryStore::new());
+        let archive = |a| ArchiveCreate {
+            pack_threshold: 6000,
+            pack_size: 1024 * 1024,
+            compressor: Some(Compressor::new(Codec::Zstd, None).unwrap()),
+            ..a
+        };
+        run(round_trip("compressed-packs", store.clone(), archive, None));
+        run(async {
+            // only the text is compressed in the pack of 10005 bytes
+            let pack_key = key_resolver::pack_key(PREFIX, &key_resolver::pack_name(0));
+            let len = content_length(store.as_ref(), &pack_key).await;
+            assert!(len > 5005 && len < 6000, "{}", len);
+        });
+    }
+
+    #[test]
+    fn round_trips_encrypted() {
+        for pack_threshold in &[0, 100] {
+            let store = Arc::new(MemoryStore::new());
+            let archive = |a| ArchiveCreate {
+                pack_threshold: *pack_threshold,
+                ..a
+            };
+            let name = format!("encrypted-{}", pack_threshold);
+            let provider = Some(key_provider());
+            run(round_trip(&name, store.clone(), archive, provider));
+            run(async {
+                // the path is hidden in the key
+                let key = key_resolver::data_key(PREFIX, RANDOM_PATH, None);
+                assert!(store.head_object(BUCKET, &key).await.unwrap().is_none());
+                let key_provider = key_provider();
+                let manifest = manifest::Reader::get(
+                    store.as_ref(),
+                    BUCKET,
+                    PREFIX,
+                    Some(key_provider.as_ref()),
+                );
+                let data_key = manifest.await.unwrap().data_key().cloned();
+                let key = key_resolver::data_key(PREFIX, RANDOM_PATH, data_key.as_ref());
+                let output = store.get_object(BUCKET, &key, ObjectRange::Whole).await;
+                let body = output.unwrap().body.try_concat().await.unwrap();
+                assert_ne!(&body[..5000], &random_bytes(5000)[..]);
+            });
+        }
+    }
+
+    #[test]
+    fn round_trips_through_local_store() {
+        let root = test_dir("local-store");
+        let store = Arc::new(LocalStore::new(root.clone()));
+        run(round_trip("local", store.clone(), |a| a, None));
+        let archive = |a| ArchiveCreate {
+            s3_prefix: "packed/".to_string(),
+            pack_threshold: 100,
+            compressor: Some(Compressor::new(Codec::Zstd, None).unwrap()),
+            ..a
+        };
+        let extract = |target: &Path| ArchiveExtract {
+            s3_prefix: "packed/".to_string(),
+            ..archive_extract(target)
+        };
+        let source = test_dir("local-packed-source");
+        let target = test_dir("local-packed-target");
+        write_contents(&source);
+        run(async {
+            let creator = CreateExecutor::new(store.clone()).with_key_provider(key_provider());
+            let extractor = ExtractExecutor::new(store).with_key_provider(key_provider());
+            let archive = archive(archive_create(&source));
+            creator.execute(archive).await.unwrap();
+            extractor.execute(extract(&target)).await.unwrap();
+        });
+        assert_contents(&target);
+        for dir in &[root, source, target] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn resumes_upload() {
+        let store = Arc::new(MemoryStore::new());
+        let source = test_dir("resume-upload-source");
+        let target = test_dir("resume-upload-target");
+        write_contents(&source);
+        let parts_uploaded = Arc::new(AtomicUsize::new(0));
+        let counter = parts_uploaded.clone();
+        run(async {
+            // the interrupted run uploaded the first part
+            let key = key_resolver::data_key(PREFIX, RANDOM_PATH, None);
+            let attributes = ObjectAttributes::default();
+            let upload_id = store.create_multipart_upload(BUCKET, &key, &attributes);
+            let upload_id = upload_id.await.unwrap();
+            let body = random_bytes(1024);
+            let part = store.upload_part(BUCKET, &key, &upload_id, 1, body);
+            part.await.unwrap();
+
+            let creator = CreateExecutor::new(store.clone()).on_progress(Arc::new(move |p| {
+                if let Progress::PartCompleted { path, .. } = p {
+                    if path == RANDOM_PATH {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            }));
+            let archive = ArchiveCreate {
+                resume: true,
+                ..archive_create(&source)
+            };
+            creator.execute(archive).await.unwrap();
+            let uploads = store.list_multipart_uploads(BUCKET, "", None).await;
+            assert!(uploads.unwrap().uploads.is_empty());
+
+            let extractor = ExtractExecutor::new(store.clone());
+            extractor.execute(archive_extract(&target)).await.unwrap();
+        });
+        assert_eq!(parts_uploaded.load(Ordering::SeqCst), 4);
+        assert_contents(&target);
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use std::env;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, AeadCore, AeadInPlace, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use futures::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::Error;
+
+pub const ALGORITHM: &str = "AES-256-GCM";
+
+pub const TAG_SIZE: usize = 16;
+
+const KEY_SIZE: usize = 32;
+
+const NONCE_SIZE: usize = 12;
+
+pub type KeyFuture<T> = future::BoxFuture<'static, Result<T, Error>>;
+
+/// Wraps the data key of an archive by the key which never leaves the provider, like KMS
+///
+/// The futures own their arguments so that the provider can call remote services.
+pub trait KeyProvider: Send + Sync {
+    fn wrap_key(&self, data_key: Vec<u8>) -> KeyFuture<Vec<u8>>;
+
+    fn unwrap_key(&self, wrapped_key: Vec<u8>) -> KeyFuture<Vec<u8>>;
+}
+
+#[derive(Clone)]
+pub struct MasterKeyProvider {
+    cipher: Aes256Gcm,
+}
+
+impl MasterKeyProvider {
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        let key = if key.len() == KEY_SIZE {
+            key.to_vec()
+        } else {
+            let text = String::from_utf8_lossy(key);
+            base64::decode(text.trim())
+                .map_err(|_| "master key must be 32 bytes or base64 of them")?
+        };
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| "master key must be 32 bytes")?;
+        Ok(MasterKeyProvider { cipher })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::new(&std::fs::read(path)?)
+    }
+
+    pub fn from_env(name: &str) -> Result<Self, Error> {
+        let key = env::var(name).map_err(|e| format!("failed to read {}: {}", name, e))?;
+        Self::new(key.as_bytes())
+    }
+}
+
+impl KeyProvider for MasterKeyProvider {
+    /// The wrapped key is the nonce followed by the encrypted data key
+    fn wrap_key(&self, data_key: Vec<u8>) -> KeyFuture<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let result: Result<_, Error> = self
+            .cipher
+            .encrypt(&nonce, data_key.as_slice())
+            .map(|sealed| nonce.into_iter().chain(sealed).collect())
+            .map_err(|_| "failed to wrap data key".into());
+        future::ready(result).boxed()
+    }
+
+    fn unwrap_key(&self, wrapped_key: Vec<u8>) -> KeyFuture<Vec<u8>> {
+        let result: Result<_, Error> = match wrapped_key.len() {
+            len if len > NONCE_SIZE => {
+                let (nonce, sealed) = wrapped_key.split_at(NONCE_SIZE);
+                self.cipher
+                    .decrypt(Nonce::from_slice(nonce), sealed)
+                    .map_err(|_| "failed to unwrap data key, the master key may be wrong".into())
+            }
+            _ => Err("invalid wrapped key".into()),
+        };
+        future::ready(result).boxed()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveEncryption {
+    pub algorithm: String,
+    pub wrapped_key: String,
+}
+
+/// The key which encrypts the parts of the files and the entries of the manifest of an archive
+///
+/// Each of them is encrypted with its own nonce and authenticated with the associated data,
+/// which binds the part to the path and the part number.
+/// The paths are hidden, but the sizes of the objects and the number of the files are not.
+#[derive(Clone)]
+pub(crate) struct DataKey {
+    cipher: Aes256Gcm,
+    names: Hmac<Sha256>,
+}
+
+impl DataKey {
+    pub async fn generate(provider: &dyn KeyProvider) -> Result<(Self, ArchiveEncryption), Error> {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let wrapped_key = provider.wrap_key(key.to_vec()).await?;
+        let encryption = ArchiveEncryption {
+            algorithm: ALGORITHM.to_string(),
+            wrapped_key: base64::encode(&wrapped_key),
+        };
+        Ok((DataKey::new(&key)?, encryption))
+    }
+
+    pub async fn unwrap(
+        provider: &dyn KeyProvider,
+        encryption: &ArchiveEncryption,
+    ) -> Result<Self, Error> {
+        if encryption.algorithm != ALGORITHM {
+            return Err(format!("unknown encryption algorithm: {}", encryption.algorithm).into());
+        }
+        let wrapped_key =
+            base64::decode(&encryption.wrapped_key).map_err(|_| "invalid wrapped key")?;
+        let key = provider.unwrap_key(wrapped_key).await?;
+        DataKey::new(&key)
+    }
+
+    fn new(key: &[u8]) -> Result<Self, Error> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "data key must be 32 bytes")?;
+        let names = Hmac::new_varkey(key).map_err(|_| "data key must be 32 bytes")?;
+        Ok(DataKey { cipher, names })
+    }
+
+    /// The name of the object which doesn't tell the path without the key
+    pub fn object_name(&self, path: &str) -> String {
+        let mut mac = self.names.clone();
+        mac.input(path.as_bytes());
+        mac.result()
+            .code()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Encrypts the data, appending the tag, and returns it with the nonce in base64
+    pub fn seal(&self, mut data: Vec<u8>, aad: &[u8]) -> Result<(Vec<u8>, String), Error> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        self.cipher
+            .encrypt_in_place(&nonce, aad, &mut data)
+            .map_err(|_| "failed to encrypt")?;
+        Ok((data, base64::encode(&nonce)))
+    }
+
+    pub fn open(&self, nonce: &str, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = base64::decode(nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == NONCE_SIZE)
+            .ok_or("invalid nonce in manifest")?;
+        let payload = Payload { msg: data, aad };
+        let opened = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| {
+                Error::integrity(format!(
+                    "failed to decrypt {}, which may be tampered",
+                    String::from_utf8_lossy(aad)
+                ))
+            })?;
+        Ok(opened)
+    }
+}
+
+pub(crate) fn part_aad(path: &str, part_number: i64) -> Vec<u8> {
+    format!("{}\t{}", path, part_number).into_bytes()
+}
@@ -3,6 +3,7 @@ use std::error::Error as StdError;
 
 use tokio::{io, task, sync::mpsc};
 use rusoto_core::RusotoError;
+use nix::errno::Errno;
 
 use super::chan_exec;
 
@@ -35,30 +36,159 @@ impl From<&'static str> for StaticStrError {
 }
 
 #[derive(Debug)]
+pub struct ChecksumError {
+    pub path: String,
+    pub part_number: Option<i64>,
+    pub expected: u32,
+    pub actual: u32,
+}
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "checksum mismatch in {}", self.path)?;
+        if let Some(part_number) = self.part_number {
+            write!(f, " part {}", part_number)?;
+        }
+        write!(
+            f,
+            ": expected crc32c {:08x}, got {:08x}",
+            self.expected, self.actual
+        )
+    }
+}
+impl StdError for ChecksumError {}
+
+#[derive(Debug)]
+pub struct ContextError {
+    pub context: String,
+    pub source: Box<Error>,
+}
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.context)
+    }
+}
+impl StdError for ContextError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    InvalidInput,
+    PermissionDenied,
+    NotFound,
+    Integrity,
+    Cancelled,
+    Other,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     Io(io::Error),
     Nix(nix::Error),
     ChanExec(chan_exec::Error<()>),
-    Rusoto(RusotoError<StringError>),
+    Rusoto(Box<RusotoError<StringError>>),
     JoinError(task::JoinError),
+    Json(serde_json::Error),
+    Checksum(ChecksumError),
     String(StringError),
     StaticStr(StaticStrError),
+    InvalidInput(StringError),
+    NotFound(StringError),
+    Integrity(StringError),
+    Context(ContextError),
+    Cancelled,
+}
+impl Error {
+    pub fn invalid_input<S: Into<String>>(message: S) -> Self {
+        Self::InvalidInput(StringError(message.into()))
+    }
+
+    pub fn not_found<S: Into<String>>(message: S) -> Self {
+        Self::NotFound(StringError(message.into()))
+    }
+
+    pub fn integrity<S: Into<String>>(message: S) -> Self {
+        Self::Integrity(StringError(message.into()))
+    }
+
+    pub fn context<S: Into<String>>(self, context: S) -> Self {
+        Self::Context(ContextError {
+            context: context.into(),
+            source: Box::new(self),
+        })
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Context(e) => e.source.kind(),
+            Self::InvalidInput(_) => ErrorKind::InvalidInput,
+            Self::NotFound(_) => ErrorKind::NotFound,
+            Self::Checksum(_) | Self::Integrity(_) => ErrorKind::Integrity,
+            Self::Cancelled => ErrorKind::Cancelled,
+            Self::Io(e) => match e.kind() {
+                io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+                io::ErrorKind::NotFound => ErrorKind::NotFound,
+                _ => ErrorKind::Other,
+            },
+            Self::Nix(nix::Error::Sys(Errno::EACCES))
+            | Self::Nix(nix::Error::Sys(Errno::EPERM)) => ErrorKind::PermissionDenied,
+            Self::Rusoto(e) => match e.as_ref() {
+                RusotoError::Credentials(_) => ErrorKind::PermissionDenied,
+                // NoSuchKey and NoSuchUpload are turned into `Error::NotFound` by the stores,
+                // and S3 responds to HEAD and the codes unknown to rusoto like NoSuchBucket
+                // by the status only
+                RusotoError::Unknown(response) => match response.status.as_u16() {
+                    401 | 403 => ErrorKind::PermissionDenied,
+                    404 => ErrorKind::NotFound,
+                    _ => ErrorKind::Other,
+                },
+                _ => ErrorKind::Other,
+            },
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// The error shows its source, so the message repeating the previous one is left out.
+    pub fn chain(&self) -> Vec<String> {
+        let mut messages: Vec<String> = Vec::new();
+        let mut next: Option<&(dyn StdError + 'static)> = Some(self);
+        while let Some(e) = next {
+            let message = e.to_string();
+            if messages.last() != Some(&message) {
+                messages.push(message);
+            }
+            next = e.source();
+        }
+        messages
+    }
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.source().unwrap())
+        match self.source() {
+            Some(e) => write!(f, "{}", e),
+            None => write!(f, "cancelled"),
+        }
     }
 }
 impl StdError for Error {
     fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match self {
-            &Self::Io(ref e) => Some(e),
-            &Self::Nix(ref e) => Some(e),
-            &Self::ChanExec(ref e) => Some(e),
-            &Self::Rusoto(ref e) => Some(e),
-            &Self::JoinError(ref e) => Some(e),
-            &Self::String(ref e) => Some(e),
-            &Self::StaticStr(ref e) => Some(e),
+            Self::Io(e) => Some(e),
+            Self::Nix(e) => Some(e),
+            Self::ChanExec(e) => Some(e),
+            Self::Rusoto(e) => Some(e.as_ref()),
+            Self::JoinError(e) => Some(e),
+            Self::Json(e) => Some(e),
+            Self::Checksum(e) => Some(e),
+            Self::String(e) => Some(e),
+            Self::StaticStr(e) => Some(e),
+            Self::InvalidInput(e) | Self::NotFound(e) | Self::Integrity(e) => Some(e),
+            Self::Context(e) => Some(e),
+            Self::Cancelled => None,
         }
     }
 }
@@ -87,14 +217,14 @@ where
     E: fmt::Display,
 {
     fn from(e: RusotoError<E>) -> Self {
-        Self::Rusoto(match e {
+        Self::Rusoto(Box::new(match e {
             RusotoError::Service(e) => RusotoError::Service(format!("{}", e).into()),
             RusotoError::HttpDispatch(e) => RusotoError::HttpDispatch(e),
             RusotoError::Credentials(e) => RusotoError::Credentials(e),
             RusotoError::Validation(e) => RusotoError::Validation(e),
             RusotoError::ParseError(e) => RusotoError::ParseError(e),
             RusotoError::Unknown(e) => RusotoError::Unknown(e),
-        })
+        }))
     }
 }
 impl From<task::JoinError> for Error {
@@ -102,6 +232,16 @@ impl From<task::JoinError> for Error {
         Self::JoinError(e)
     }
 }
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+impl From<ChecksumError> for Error {
+    fn from(e: ChecksumError) -> Self {
+        Self::Checksum(e)
+    }
+}
 impl From<StringError> for Error {
     fn from(e: StringError) -> Self {
         Self::String(e)
@@ -122,3 +262,12 @@ impl From<&'static str> for Error {
         Self::StaticStr(StaticStrError(e))
     }
 }
+
+pub(crate) trait Context<T> {
+    fn context<F: FnOnce() -> String>(self, f: F) -> Result<T, Error>;
+}
+impl<T, E: Into<Error>> Context<T> for Result<T, E> {
+    fn context<F: FnOnce() -> String>(self, f: F) -> Result<T, Error> {
+        self.map_err(|e| e.into().context(f()))
+    }
+}
@@ -1,15 +1,27 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::compat::*;
 use futures::prelude::*;
+use tokio::task;
 
-use rusoto_s3::{GetObjectOutput, GetObjectRequest, S3Client, S3};
+use globset::GlobSet;
 
-use super::file_entry::FileEntry;
+use super::cancel::{self, Cancellation};
+use super::encryption::{self, DataKey, KeyProvider, TAG_SIZE};
+use super::error::{Context, Error};
+use super::file_entry::{FileEntry, Kind, Pack};
+use super::journal::{self, Journal};
 use super::key_resolver;
+use super::manifest;
 use super::mmap;
-use super::utils::with_retry;
-use super::Error;
+use super::progress::{Progress, ProgressCallback, Reporter};
+use super::restore;
+use super::store::{ByteStream, GetObjectOutput, ObjectRange, ObjectStore};
+use super::utils::{build_glob_set, with_reported_retry};
 
 #[derive(Debug, Clone)]
 pub struct ArchiveExtract {
@@ -18,18 +30,75 @@ pub struct ArchiveExtract {
     pub directory: Option<PathBuf>,
     pub s3_bucket: String,
     pub s3_prefix: String,
+    /// The journal is relative to the directory unless it's absolute
+    pub journal: PathBuf,
+    pub resume: bool,
+    pub same_owner: bool,
+    pub range_size: usize,
+    pub paths: Vec<String>,
+    pub includes: Vec<String>,
+    pub excludes: Vec<String>,
+    pub wait_for_restore: Option<Duration>,
+}
+
+impl Default for ArchiveExtract {
+    fn default() -> Self {
+        ArchiveExtract {
+            file_concurrency: 8,
+            part_concurrency: 8,
+            directory: None,
+            s3_bucket: String::new(),
+            s3_prefix: String::new(),
+            journal: ".s3ar-journal".into(),
+            resume: false,
+            same_owner: false,
+            range_size: 16 * 1024 * 1024,
+            paths: Vec::new(),
+            includes: Vec::new(),
+            excludes: Vec::new(),
+            wait_for_restore: None,
+        }
+    }
 }
 
 pub struct ExtractExecutor {
-    s3_client: S3Client,
+    store: Arc<dyn ObjectStore>,
+    reporter: Reporter,
+    cancellation: Option<Cancellation>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
 }
 
 impl ExtractExecutor {
-    pub fn new(s3_client: S3Client) -> Self {
-        Self { s3_client }
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            reporter: Reporter::default(),
+            cancellation: None,
+            key_provider: None,
+        }
+    }
+
+    pub fn on_progress(mut self, callback: ProgressCallback) -> Self {
+        self.reporter = Reporter::new(callback);
+        self
+    }
+
+    /// Stops downloading when cancelled, leaving the journal to resume
+    pub fn with_cancellation(mut self, cancellation: Cancellation) -> Self {
+        self.cancellation = Some(cancellation);
+        self
     }
 
-    pub async fn execute(
+    pub fn with_key_provider(mut self, key_provider: Arc<dyn KeyProvider>) -> Self {
+        self.key_provider = Some(key_provider);
+        self
+    }
+
+    pub async fn execute(&self, archive: ArchiveExtract) -> Result<(), Error> {
+        cancel::unless_cancelled(self.cancellation.as_ref(), self.extract(archive)).await
+    }
+
+    async fn extract(
         &self,
         ArchiveExtract {
             file_concurrency,
@@ -37,73 +106,235 @@ impl ExtractExecutor {
             directory,
             s3_bucket,
             s3_prefix,
+            journal,
+            resume,
+            same_owner,
+            range_size,
+            paths,
+            includes,
+            excludes,
+            wait_for_restore,
         }: ArchiveExtract,
     ) -> Result<(), Error> {
-        if let Some(cwd) = directory {
-            std::env::set_current_dir(cwd).expect("failed to change current dir");
+        let directory = directory.unwrap_or_default();
+        let selection = Selection::new(paths, &includes, &excludes)?;
+        if range_size == 0 {
+            return Err(Error::invalid_input("range size must be positive"));
         }
 
-        let get_object_request = GetObjectRequest {
-            bucket: s3_bucket.clone(),
-            key: key_resolver::manifest_key(&s3_prefix),
-            ..Default::default()
-        };
-        let GetObjectOutput { body, .. } = self
-            .s3_client
-            .get_object(get_object_request)
-            .compat()
+        let key_provider = self.key_provider.as_deref();
+        let manifest =
+            manifest::Reader::get(self.store.as_ref(), &s3_bucket, &s3_prefix, key_provider)
+                .await?;
+        let data_key = manifest.data_key().cloned().map(Arc::new);
+        let (_, entries) = manifest.into_parts();
+        // the entries are read ahead to wait for the objects and to count the files
+        let entries: Vec<_> = entries.try_collect().await?;
+        if let Some(interval) = wait_for_restore {
+            let keys: BTreeSet<_> = entries
+                .iter()
+                .filter(|entry| selection.is_selected(entry.path()))
+                .filter_map(|entry| {
+                    key_resolver::object_key(&s3_prefix, entry, data_key.as_deref())
+                })
+                .collect();
+            restore::wait_until_available(
+                self.store.as_ref(),
+                &s3_bucket,
+                keys.into_iter().collect(),
+                file_concurrency,
+                interval,
+                &self.reporter,
+            )
             .await?;
+        }
 
+        let journal = directory.join(journal);
+        let journal_path = journal.display().to_string();
+        let journal = Journal::open(journal, resume)
+            .await
+            .context(|| format!("failed to open journal {}", journal_path))?;
+        let journal = Arc::new(journal);
+        let (files, bytes) = entries
+            .iter()
+            .filter(|entry| entry.kind() == Kind::File && selection.is_selected(entry.path()))
+            .filter(|entry| !journal.is_completed(entry.path()))
+            .fold((0, 0), |(files, bytes), entry| {
+                (files + 1, bytes + entry.size() as u64)
+            });
+        self.reporter.report(Progress::Total { files, bytes });
         let mp_downloader = MultipartDownloadExecutor {
-            s3_client: self.s3_client.clone(),
+            store: self.store.clone(),
+            journal: journal.clone(),
+            reporter: self.reporter.clone(),
+            directory: directory.clone(),
+            data_key: data_key.clone(),
+            range_size,
+            resume,
+            same_owner,
         };
         let mp_downloader = &mp_downloader;
 
-        body.expect("no manifest content")
-            .compat()
-            .into_async_read()
-            .lines()
-            .map_err(Error::from)
-            .and_then(|line| {
+        // directories and links are created after all files have been downloaded
+        // because hard links need their linked files
+        let mut nodes = Vec::new();
+        // packed files are downloaded together after the others
+        let mut packs = BTreeMap::<String, Vec<FileEntry>>::new();
+        stream::iter(entries)
+            .map(Ok::<_, Error>)
+            .try_filter(|entry| {
+                if !selection.is_selected(entry.path()) {
+                    return future::ready(false);
+                }
+                let link = entry.link().unwrap_or_default();
+                if entry.kind() == Kind::HardLink && !selection.is_selected(link) {
+                    self.reporter.report(Progress::Skipped {
+                        path: entry.path().to_string(),
+                        reason: format!("hard link to {} which is not selected", link),
+                    });
+                    return future::ready(false);
+                }
+                future::ready(true)
+            })
+            .try_filter(|entry| {
+                if entry.kind() != Kind::File {
+                    nodes.push(entry.clone());
+                }
+                future::ready(entry.kind() == Kind::File)
+            })
+            .try_filter_map(|entry| {
+                let journal = journal.clone();
+                let directory = &directory;
                 async move {
-                    let mut cols = line.split("\t");
-                    let size = cols.next().unwrap().parse().map_err(|e| format!("{}", e))?;
-                    let path = cols.next().ok_or("no path in manifest")?;
-                    Ok(FileEntry::new(path.to_string(), size))
+                    if !journal.is_completed(entry.path()) {
+                        return Ok(Some(entry));
+                    }
+                    // the file downloaded by the interrupted run is downloaded again
+                    // if it doesn't match, e.g. the crash lost the data written last
+                    if entry.verify_file(directory).await.is_err() {
+                        return Ok(Some(entry));
+                    }
+                    // its metadata may not have been restored
+                    entry.restore_metadata(directory, same_owner).await?;
+                    Ok(None)
                 }
             })
+            .try_filter(|entry| {
+                if let Some(pack) = entry.pack() {
+                    let entries = packs.entry(pack.key.clone()).or_default();
+                    entries.push(entry.clone());
+                }
+                future::ready(entry.pack().is_none())
+            })
             .map_ok(|entry| {
                 let s3_prefix = s3_prefix.clone();
                 let s3_bucket = s3_bucket.clone();
 
-                let source_key = key_resolver::data_key(&s3_prefix, entry.path());
+                let source_key =
+                    key_resolver::data_key(&s3_prefix, entry.path(), data_key.as_deref());
                 let object_download = ObjectDownload {
                     source_bucket: s3_bucket,
                     source_key,
                 };
-                with_retry(10, 1, 5, move || {
+                self.reporter.report(Progress::FileStarted {
+                    path: entry.path().to_string(),
+                    size: entry.size(),
+                });
+                with_reported_retry(&self.reporter, 10, 1, 5, move || {
                     mp_downloader.execute(object_download.clone(), entry.clone())
                 })
             })
             .try_buffer_unordered(file_concurrency)
             .try_flatten()
-            .try_for_each_concurrent(part_concurrency, |fut| {
-                async move {
-                    fut.await
-                }
+            .try_for_each_concurrent(part_concurrency, |fut| fut)
+            .await?;
+
+        let pack_downloader = PackDownloadExecutor {
+            store: self.store.clone(),
+            journal: journal.clone(),
+            reporter: self.reporter.clone(),
+            directory: directory.clone(),
+            data_key,
+            resume,
+            same_owner,
+        };
+        stream::iter(packs)
+            .map(Ok)
+            .try_for_each_concurrent(file_concurrency, |(pack, entries)| {
+                let pack_key = key_resolver::pack_key(&s3_prefix, &pack);
+                pack_downloader.execute(&s3_bucket, pack_key, entries)
             })
-            .await
+            .await?;
+
+        // directories come first so that the metadata of them is restored at last
+        nodes.sort_by_key(|node| node.kind() != Kind::Directory);
+        for node in &nodes {
+            node.create_node(&directory, resume)
+                .await
+                .context(|| format!("failed to create {}", node.path()))?;
+        }
+        for node in nodes.iter().rev() {
+            node.restore_metadata(&directory, same_owner)
+                .await
+                .context(|| format!("failed to restore metadata of {}", node.path()))?;
+        }
+
+        journal.remove().await
+    }
+}
+
+struct Selection {
+    paths: Vec<String>,
+    includes: GlobSet,
+    excludes: GlobSet,
+}
+
+impl Selection {
+    fn new(paths: Vec<String>, includes: &[String], excludes: &[String]) -> Result<Self, Error> {
+        let paths = paths
+            .into_iter()
+            .map(|path| path.trim_end_matches('/').to_string())
+            .collect();
+        Ok(Selection {
+            paths,
+            includes: build_glob_set(includes)?,
+            excludes: build_glob_set(excludes)?,
+        })
+    }
+
+    /// Everything is selected unless the paths or the include patterns are given,
+    /// and the exclude patterns take precedence over them
+    fn is_selected(&self, path: &str) -> bool {
+        if self.excludes.is_match(path) {
+            return false;
+        }
+        if self.paths.is_empty() && self.includes.is_empty() {
+            return true;
+        }
+        let under = |dir: &String| {
+            path.strip_prefix(dir.as_str())
+                .map(|rest| rest.is_empty() || rest.starts_with('/'))
+                .unwrap_or(false)
+        };
+        self.paths.iter().any(under) || self.includes.is_match(path)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct ObjectDownload {
+struct ObjectDownload {
     source_bucket: String,
     source_key: String,
 }
 
-pub struct MultipartDownloadExecutor {
-    s3_client: S3Client,
+struct MultipartDownloadExecutor {
+    store: Arc<dyn ObjectStore>,
+    journal: Arc<Journal>,
+    reporter: Reporter,
+    directory: PathBuf,
+    data_key: Option<Arc<DataKey>>,
+    range_size: usize,
+    resume: bool,
+    same_owner: bool,
 }
 
 impl MultipartDownloadExecutor {
@@ -115,58 +346,592 @@ impl MultipartDownloadExecutor {
         }: ObjectDownload,
         target: FileEntry,
     ) -> Result<impl Stream<Item = Result<impl Future<Output = Result<(), Error>>, Error>>, Error> {
-        let handle = target.create().await?;
+        let context = format!("failed to download {} from {}", target.path(), source_key);
+        let handle = if self.resume {
+            target.reopen(&self.directory).await
+        } else {
+            target.create(&self.directory).await
+        };
+        let handle = handle.context(|| context.clone())?;
         let chunker = mmap::Chunker::new(handle);
-        let s3 = self.s3_client.clone();
-        Ok(stream::try_unfold((chunker, None), move |(mut chunker, state)| {
-            let s3 = s3.clone();
-            let bucket = source_bucket.clone();
-            let key = source_key.clone();
-            async move {
-                let (part, parts_count, next_part_number) =
-                    if let Some((part_number, parts_count)) = state {
-                        if part_number > parts_count {
-                            return Ok(None);
+        let store = self.store.clone();
+        let journal = self.journal.clone();
+        let entry = target.clone();
+        // the completed file is downloaded again as a whole if it's broken
+        let (completed_parts, range_size) = if self.journal.is_completed(target.path()) {
+            (Arc::default(), None)
+        } else {
+            let completed_parts = self.journal.completed_parts(target.path());
+            (completed_parts, self.journal.range_size(target.path()))
+        };
+        let compression = target.compression().cloned();
+        let parts = match (&compression, target.encryption()) {
+            (Some(compression), _) => Some(Parts::Frames(compression.frames.len() as i64)),
+            // the object is got by the parts because each of them is encrypted by itself,
+            // and the part 1 of the object uploaded without multipart is the whole object
+            (None, Some(encryption)) => Some(Parts::Multipart(encryption.nonces.len() as i64)),
+            (None, None) => completed_parts
+                .values()
+                .next()
+                .map(|part| match range_size {
+                    Some(size) => Parts::Ranges {
+                        count: part.parts_count,
+                        size,
+                    },
+                    None => Parts::Multipart(part.parts_count),
+                }),
+        };
+        let range_size = self.range_size;
+        let ranges_journal = self.journal.clone();
+        let file_path = target.path().to_string();
+        let file_size = target.size();
+        // the metadata is restored by the part which completes the file
+        let done_parts_count = Arc::new(AtomicI64::new(0));
+        let same_owner = self.same_owner;
+        let directory = self.directory.clone();
+        let reporter = self.reporter.clone();
+        let data_key = self.data_key.clone();
+        let encrypted = target.encryption().is_some();
+        let part_context = context.clone();
+        let file_entry = target.clone();
+        let state = (chunker, 1, parts);
+        Ok(
+            stream::try_unfold(state, move |(mut chunker, part_number, parts)| {
+                let store = store.clone();
+                let journal = ranges_journal.clone();
+                let file_path = file_path.clone();
+                let bucket = source_bucket.clone();
+                let key = source_key.clone();
+                let completed_parts = completed_parts.clone();
+                let compression = compression.clone();
+                let entry = file_entry.clone();
+                async move {
+                    let started = Instant::now();
+                    // the part downloaded by the interrupted run is kept if it matches
+                    let mut broken_chunk = None;
+                    if let (Some(part), Some(parts)) = (completed_parts.get(&part_number), parts) {
+                        if chunker.size() < part.len {
+                            return Err("journal doesn't match the file size".into());
+                        }
+                        let chunk = chunker.take_chunk(part.len);
+                        // the file which isn't verified by the parts is verified as a whole at last
+                        let verified = entry.verify_part(part_number, &chunk).is_ok();
+                        if verified || !parts.verifies(&entry) {
+                            return Ok(Some((
+                                (None, chunk, part_number, parts, started),
+                                (chunker, part_number + 1, Some(parts)),
+                            )));
+                        }
+                        broken_chunk = Some(chunk);
+                    }
+                    let (part, parts) = match parts {
+                        Some(parts) if part_number > parts.count() => return Ok(None),
+                        Some(parts) => {
+                            let range = match &compression {
+                                Some(compression) => {
+                                    let (offset, len) = compression.frame_range(part_number);
+                                    ObjectRange::Bytes(offset, len)
+                                }
+                                None => parts.object_range(part_number, file_size),
+                            };
+                            (store.get_object(&bucket, &key, range).await?, parts)
+                        }
+                        // S3 rejects any range of an empty object, so nothing is got
+                        None if file_size == 0 => (
+                            empty_object(),
+                            Parts::Ranges {
+                                count: 1,
+                                size: range_size,
+                            },
+                        ),
+                        None => {
+                            let first_part =
+                                get_first_part(store.as_ref(), &bucket, &key, range_size);
+                            let (part, parts) = first_part.await?;
+                            if let Parts::Ranges { count, size } = parts {
+                                if count > 1 {
+                                    journal.start_ranges(&file_path, size).await?;
+                                }
+                            }
+                            (part, parts)
+                        }
+                    };
+                    // the compressed or encrypted part is decoded into the chunk of the file
+                    let len = match (&compression, encrypted) {
+                        (Some(compression), _) => cmp::min(compression.frame_size, chunker.size()),
+                        (None, true) if part.content_length < TAG_SIZE => {
+                            return Err(format!("object {} has no tag", key).into());
                         }
-                        let part = get_part(&s3, bucket.clone(), key.clone(), part_number).await?;
-                        (part, parts_count, part_number + 1)
-                    } else {
-                        let part = get_part(&s3, bucket.clone(), key.clone(), 1).await?;
-                        let parts_count = part.parts_count.ok_or("no parts count header")?;
-                        (part, parts_count, 2)
+                        (None, true) => part.content_length - TAG_SIZE,
+                        (None, false) => part.content_length,
                     };
-                let content_length = part.content_length.ok_or("no content length header")?;
-                let chunk = chunker.take_chunk(content_length as usize);
-                return Ok::<_, Error>(Some((
-                    (part, chunk),
-                    (chunker, Some((next_part_number, parts_count))),
-                )));
+                    let chunk = match broken_chunk {
+                        Some(chunk) if chunk.len() == len => chunk,
+                        Some(_) => return Err("journal doesn't match the object".into()),
+                        None if chunker.size() < len => {
+                            return Err(format!("object {} is larger than the file", key).into());
+                        }
+                        None => chunker.take_chunk(len),
+                    };
+                    Ok::<_, Error>(Some((
+                        (Some(part), chunk, part_number, parts, started),
+                        (chunker, part_number + 1, Some(parts)),
+                    )))
+                }
+            })
+            .map_err(move |e| e.context(context.clone()))
+            .map_ok(move |(source, mut target, part_number, parts, started)| {
+                let journal = journal.clone();
+                let entry = entry.clone();
+                let data_key = data_key.clone();
+                let done_parts_count = done_parts_count.clone();
+                let directory = directory.clone();
+                let reporter = reporter.clone();
+                let context = part_context.clone();
+                async move {
+                    let completed_part = journal::CompletedPart {
+                        parts_count: parts.count(),
+                        len: target.len(),
+                    };
+                    let size = completed_part.len;
+                    if let Some(source) = source {
+                        if entry.compression().is_some() || entry.encryption().is_some() {
+                            let data = source.body.try_concat().await?;
+                            target = decode(&entry, data_key, part_number, data, target).await?;
+                        } else {
+                            let source_read = source.body.into_async_read();
+                            let mut target_write = futures::io::Cursor::new(&mut target[..]);
+                            futures::io::copy(source_read, &mut target_write).await?;
+                        }
+                        if parts.verifies(&entry) {
+                            entry.verify_part(part_number, &target)?;
+                        }
+                        // the part must be on the disk before the journal tells it's completed
+                        task::spawn_blocking(move || target.sync()).await??;
+                        journal
+                            .complete_part(entry.path(), part_number, completed_part)
+                            .await?;
+                        reporter.report(Progress::PartCompleted {
+                            path: entry.path().to_string(),
+                            part_number,
+                            size,
+                            elapsed: started.elapsed(),
+                        });
+                    }
+                    let is_last =
+                        done_parts_count.fetch_add(1, Ordering::SeqCst) + 1 == parts.count();
+                    if is_last && !parts.verifies(&entry) {
+                        entry.verify_file(&directory).await?;
+                    }
+                    if is_last {
+                        entry.restore_metadata(&directory, same_owner).await?;
+                        reporter.report(Progress::FileCompleted {
+                            path: entry.path().to_string(),
+                        });
+                    }
+                    Ok::<_, Error>(())
+                }
+                .map_err(|e| e.context(context))
+            }),
+        )
+    }
+}
+
+/// The bytes between the packed files which are got rather than splitting the GET
+const MAX_PACK_GAP: usize = 1024 * 1024;
+
+struct PackDownloadExecutor {
+    store: Arc<dyn ObjectStore>,
+    journal: Arc<Journal>,
+    reporter: Reporter,
+    directory: PathBuf,
+    data_key: Option<Arc<DataKey>>,
+    resume: bool,
+    same_owner: bool,
+}
+
+impl PackDownloadExecutor {
+    async fn execute(
+        &self,
+        bucket: &str,
+        key: String,
+        mut entries: Vec<FileEntry>,
+    ) -> Result<(), Error> {
+        let pack = |entry: &FileEntry| entry.pack().cloned().expect("no pack");
+        entries.sort_by_key(|entry| pack(entry).offset);
+        let mut entries = entries.into_iter().peekable();
+        while let Some(first) = entries.next() {
+            let start = pack(&first).offset;
+            let mut end = start + pack(&first).len;
+            let mut group = vec![first];
+            while let Some(next) = entries.peek() {
+                if pack(next).offset > end + MAX_PACK_GAP {
+                    break;
+                }
+                end = cmp::max(end, pack(next).offset + pack(next).len);
+                group.extend(entries.next());
             }
-        })
-        .map_ok(|(source, mut target)| {
-            async move {
-                let source_read =
-                    source.body.ok_or("no body")?.compat().into_async_read();
-                let mut target_write = futures::io::Cursor::new(&mut target[..]);
-                futures::io::copy(source_read, &mut target_write).await?;
-                Ok(())
+            let len = end - start;
+            let started = Instant::now();
+            let body = if len > 0 {
+                with_reported_retry(&self.reporter, 10, 1, 5, || {
+                    self.get_range(bucket, &key, start, len)
+                })
+                .await
+                .context(|| format!("failed to download pack {}", key))?
+            } else {
+                // only empty files
+                Vec::new()
+            };
+            for entry in group {
+                let Pack { offset, len, .. } = pack(&entry);
+                self.write(&entry, &body[offset - start..offset - start + len], started)
+                    .await
+                    .context(|| format!("failed to extract {} from {}", entry.path(), key))?;
             }
-        }))
+        }
+        Ok(())
+    }
+
+    async fn get_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let range = ObjectRange::Bytes(offset, len);
+        let output = self.store.get_object(bucket, key, range).await?;
+        let body: Vec<u8> = output.body.try_concat().await?;
+        if body.len() != len {
+            let message = format!("pack {} is smaller than the manifest", key);
+            return Err(Error::integrity(message));
+        }
+        Ok(body)
     }
+
+    async fn write(&self, entry: &FileEntry, data: &[u8], started: Instant) -> Result<(), Error> {
+        if data.len() != entry.object_size() {
+            let message = format!("size of {} in pack doesn't match", entry.path());
+            return Err(Error::integrity(message));
+        }
+        self.reporter.report(Progress::FileStarted {
+            path: entry.path().to_string(),
+            size: entry.size(),
+        });
+        let handle = if self.resume {
+            entry.reopen(&self.directory).await?
+        } else {
+            entry.create(&self.directory).await?
+        };
+        let mut target = mmap::Chunker::new(handle).take_chunk(entry.size());
+        if entry.compression().is_some() || entry.encryption().is_some() {
+            let data_key = self.data_key.clone();
+            target = decode(entry, data_key, 1, data.to_vec(), target).await?;
+        } else {
+            target.copy_from_slice(data);
+        }
+        entry.verify_part(1, &target)?;
+        task::spawn_blocking(move || target.sync()).await??;
+        let completed_part = journal::CompletedPart {
+            parts_count: 1,
+            len: entry.size(),
+        };
+        self.journal
+            .complete_part(entry.path(), 1, completed_part)
+            .await?;
+        self.reporter.report(Progress::PartCompleted {
+            path: entry.path().to_string(),
+            part_number: 1,
+            size: entry.size(),
+            elapsed: started.elapsed(),
+        });
+        entry
+            .restore_metadata(&self.directory, self.same_owner)
+            .await?;
+        self.reporter.report(Progress::FileCompleted {
+            path: entry.path().to_string(),
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Parts {
+    Multipart(i64),
+    Ranges { count: i64, size: usize },
+    Frames(i64),
 }
 
-async fn get_part(
-    s3: &S3Client,
-    bucket: String,
-    key: String,
+impl Parts {
+    fn count(&self) -> i64 {
+        match *self {
+            Parts::Multipart(count) | Parts::Ranges { count, .. } | Parts::Frames(count) => count,
+        }
+    }
+
+    fn verifies(&self, entry: &FileEntry) -> bool {
+        match *self {
+            Parts::Multipart(count) | Parts::Frames(count) => entry.verifies_parts(count),
+            Parts::Ranges { .. } => false,
+        }
+    }
+
+    fn object_range(&self, part_number: i64, object_size: usize) -> ObjectRange {
+        match *self {
+            Parts::Multipart(_) => ObjectRange::Part(part_number),
+            Parts::Frames(_) => unreachable!("frames are got by the ranges in the manifest"),
+            Parts::Ranges { size, .. } => {
+                let offset = (part_number - 1) as usize * size;
+                ObjectRange::Bytes(offset, cmp::min(size, object_size - offset))
+            }
+        }
+    }
+}
+
+async fn decode(
+    entry: &FileEntry,
+    data_key: Option<Arc<DataKey>>,
     part_number: i64,
-) -> Result<GetObjectOutput, Error> {
-    let request = GetObjectRequest {
-        bucket,
-        key,
-        part_number: Some(part_number),
-        ..Default::default()
+    data: Vec<u8>,
+    mut target: mmap::Chunk,
+) -> Result<mmap::Chunk, Error> {
+    let nonce = match entry.encryption() {
+        Some(encryption) => {
+            let nonce = encryption.nonces.get(part_number as usize - 1).cloned();
+            Some(nonce.ok_or("no nonce of the part in manifest")?)
+        }
+        None => None,
     };
-    let output = s3.get_object(request).compat().await?;
-    Ok(output)
+    let codec = entry.compression().map(|compression| compression.codec);
+    let aad = encryption::part_aad(entry.path(), part_number);
+    task::spawn_blocking(move || {
+        let data = match (nonce, data_key) {
+            (Some(nonce), Some(data_key)) => data_key.open(&nonce, &data, &aad)?,
+            (Some(_), None) => return Err("no data key to decrypt the archive".into()),
+            (None, _) => data,
+        };
+        match codec {
+            Some(codec) => codec.decompress(&data, &mut target)?,
+            None if data.len() == target.len() => target.copy_from_slice(&data),
+            None => {
+                return Err(Error::integrity(
+                    "decrypted data doesn't match the size of the file",
+                ))
+            }
+        }
+        Ok(target)
+    })
+    .await?
+}
+
+/// The output in place of getting an empty object
+fn empty_object() -> GetObjectOutput {
+    GetObjectOutput {
+        body: stream::empty().boxed(),
+        content_length: 0,
+        parts_count: None,
+    }
+}
+
+/// The object uploaded without multipart, e.g. by PutObject or copied, has no parts count
+/// and the part 1 is the whole object, which is got again by ranges if it's larger than a range.
+async fn get_first_part(
+    store: &dyn ObjectStore,
+    bucket: &str,
+    key: &str,
+    range_size: usize,
+) -> Result<(GetObjectOutput, Parts), Error> {
+    let part = store.get_object(bucket, key, ObjectRange::Part(1)).await?;
+    let object_size = part.content_length;
+    if let Some(parts_count) = part.parts_count {
+        return Ok((part, Parts::Multipart(parts_count)));
+    }
+    if object_size <= range_size {
+        let parts = Parts::Ranges {
+            count: 1,
+            size: range_size,
+        };
+        return Ok((part, parts));
+    }
+    // the first range is read from the whole object instead of getting it again
+    let parts = Parts::Ranges {
+        count: object_size.div_ceil(range_size) as i64,
+        size: range_size,
+    };
+    let part = GetObjectOutput {
+        body: take_bytes(part.body, range_size),
+        content_length: range_size,
+        parts_count: None,
+    };
+    Ok((part, parts))
+}
+
+/// The rest of the body is dropped without being read
+fn take_bytes(body: ByteStream, len: usize) -> ByteStream {
+    stream::unfold((body, len), |(mut body, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        match body.next().await? {
+            Ok(mut data) => {
+                data.truncate(remaining);
+                let remaining = remaining - data.len();
+                Some((Ok(data), (body, remaining)))
+            }
+            Err(e) => Some((Err(e), (body, 0))),
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::create::CreateExecutor;
+    use crate::store::{MemoryStore, ObjectAttributes};
+    use crate::testing::*;
+    use crate::utils::test_dir;
+
+    fn selection(paths: &[&str], includes: &[&str], excludes: &[&str]) -> Selection {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        Selection::new(strings(paths), &strings(includes), &strings(excludes)).unwrap()
+    }
+
+    #[test]
+    fn selects_everything_by_default() {
+        let selection = selection(&[], &[], &[]);
+        assert!(selection.is_selected("a"));
+        assert!(selection.is_selected("b/c"));
+    }
+
+    #[test]
+    fn selects_paths_and_under_directories() {
+        let selection = selection(&["a", "b/"], &[], &[]);
+        assert!(selection.is_selected("a"));
+        assert!(selection.is_selected("a/c"));
+        assert!(selection.is_selected("b"));
+        assert!(selection.is_selected("b/c/d"));
+        assert!(!selection.is_selected("ab"));
+        assert!(!selection.is_selected("c/a"));
+    }
+
+    #[test]
+    fn selects_paths_or_includes() {
+        let selection = selection(&["a"], &["*.txt"], &[]);
+        assert!(selection.is_selected("a/b.bin"));
+        assert!(selection.is_selected("c/d.txt"));
+        assert!(!selection.is_selected("c/d.bin"));
+    }
+
+    #[test]
+    fn excludes_take_precedence() {
+        let selection = selection(&["a"], &["*.txt"], &["*.log", "a/tmp/**"]);
+        assert!(selection.is_selected("a/b"));
+        assert!(!selection.is_selected("a/b.log"));
+        assert!(!selection.is_selected("a/tmp/c"));
+        assert!(selection.is_selected("c.txt"));
+        assert!(!selection.is_selected("tmp.log"));
+    }
+
+    #[test]
+    fn excludes_without_includes_select_the_rest() {
+        let selection = selection(&[], &[], &["*.log"]);
+        assert!(selection.is_selected("a/b"));
+        assert!(!selection.is_selected("a/b.log"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let strings = vec!["a[".to_string()];
+        assert!(Selection::new(Vec::new(), &strings, &[]).is_err());
+    }
+
+    #[test]
+    fn extracts_objects_copied_with_other_parts() {
+        let store = Arc::new(MemoryStore::new());
+        let source = test_dir("copied-source");
+        let target = test_dir("copied-target");
+        write_contents(&source);
+        run(async {
+            let creator = CreateExecutor::new(store.clone());
+            creator.execute(archive_create(&source)).await.unwrap();
+            // the object of 5 parts is copied by 2 parts
+            let key = key_resolver::data_key(PREFIX, RANDOM_PATH, None);
+            let attributes = ObjectAttributes::default();
+            let upload_id = store.create_multipart_upload(BUCKET, &key, &attributes);
+            let upload_id = upload_id.await.unwrap();
+            let mut parts = Vec::new();
+            for (i, body) in random_bytes(5000).chunks(2500).enumerate() {
+                let part_number = i as i64 + 1;
+                let part = store.upload_part(BUCKET, &key, &upload_id, part_number, body.to_vec());
+                parts.push((part_number, part.await.unwrap()));
+            }
+            let completed = store.complete_multipart_upload(BUCKET, &key, &upload_id, parts);
+            completed.await.unwrap();
+            let extractor = ExtractExecutor::new(store.clone());
+            extractor.execute(archive_extract(&target)).await.unwrap();
+        });
+        assert_contents(&target);
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn resumes_download() {
+        let store = Arc::new(MemoryStore::new());
+        let source = test_dir("resume-download-source");
+        let target = test_dir("resume-download-target");
+        write_contents(&source);
+        // the interrupted run downloaded the first two parts, but the crash lost the second one
+        let mut interrupted = random_bytes(5000);
+        interrupted[1024..2048].copy_from_slice(&[b'x'; 1024]);
+        fs::create_dir_all(target.join("dir/sub")).unwrap();
+        fs::write(target.join(RANDOM_PATH), &interrupted).unwrap();
+        // and the file completed by it was broken later
+        fs::write(target.join("small.txt"), b"xxxxx").unwrap();
+        let journal = format!("1\t5\t1024\t{0}\n2\t5\t1024\t{0}\n", RANDOM_PATH);
+        let journal = format!("{}1\t1\t5\tsmall.txt\n", journal);
+        fs::write(target.join(".s3ar-journal"), journal).unwrap();
+        let parts_downloaded = Arc::new(AtomicUsize::new(0));
+        let counter = parts_downloaded.clone();
+        run(async {
+            let creator = CreateExecutor::new(store.clone());
+            creator.execute(archive_create(&source)).await.unwrap();
+            let extractor = ExtractExecutor::new(store.clone()).on_progress(Arc::new(move |p| {
+                if let Progress::PartCompleted { path, .. } = p {
+                    if path == RANDOM_PATH {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            }));
+            let archive = ArchiveExtract {
+                resume: true,
+                ..archive_extract(&target)
+            };
+            extractor.execute(archive).await.unwrap();
+        });
+        assert_eq!(parts_downloaded.load(Ordering::SeqCst), 4);
+        assert_contents(&target);
+        assert!(!target.join(".s3ar-journal").exists());
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gets_first_range_of_objects_without_parts() {
+        let store = MemoryStore::new();
+        let attributes = ObjectAttributes::default();
+        let body = random_bytes(5000);
+        let put = store.put_object(BUCKET, "a", body.clone(), &attributes);
+        put.await.unwrap();
+        let (part, parts) = get_first_part(&store, BUCKET, "a", 2000).await.unwrap();
+        assert_eq!(parts.count(), 3);
+        assert_eq!(part.content_length, 2000);
+        let data = part.body.try_concat().await.unwrap();
+        assert_eq!(data, &body[..2000]);
+    }
 }
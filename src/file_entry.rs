@@ -1,50 +1,370 @@
 use std::path::Path;
-use std::io::SeekFrom;
+use std::fs::{Metadata, Permissions};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
 use tokio::prelude::*;
 use tokio::fs;
+use tokio::task;
 
-use super::error::Error;
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use serde::{Deserialize, Serialize};
+
+use super::compression::Codec;
+use super::encryption::TAG_SIZE;
+use super::error::{ChecksumError, Error};
 use super::mmap;
+use super::utils::crc32c_of_file;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    #[default]
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+}
+
+impl Kind {
+    fn is_file(&self) -> bool {
+        *self == Kind::File
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checksum {
+    crc32c: u32,
+    parts: Vec<u32>,
+}
+
+impl Checksum {
+    pub fn new(part_checksums: Vec<(u32, usize)>) -> Self {
+        let crc32c = part_checksums.iter().fold(0, |crc, &(part_crc, len)| {
+            crc32c::crc32c_combine(crc, part_crc, len)
+        });
+        let parts = part_checksums.into_iter().map(|(crc, _)| crc).collect();
+        Checksum { crc32c, parts }
+    }
+
+    pub fn crc32c(&self) -> u32 {
+        self.crc32c
+    }
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pack {
+    pub key: String,
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// How the content of the file is compressed in the object
+///
+/// The file is split into the frames of `frame_size` bytes, the last of which may be smaller,
+/// and each frame is compressed independently to be decompressed in parallel.
+/// The frames are the parts of the checksum.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    pub codec: Codec,
+    pub frame_size: usize,
+    /// The sizes of the frames in the object, which include the tags if encrypted
+    pub frames: Vec<usize>,
+}
+
+impl Compression {
+    pub fn compressed_size(&self) -> usize {
+        self.frames.iter().sum()
+    }
+
+    pub fn frame_range(&self, frame_number: i64) -> (usize, usize) {
+        let index = frame_number as usize - 1;
+        (self.frames[..index].iter().sum(), self.frames[index])
+    }
+}
+
+/// The nonces which the parts of the file are encrypted with by the data key of the archive
+///
+/// Each part, which is the frame if compressed, is encrypted after compressed
+/// and has the tag appended in the object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encryption {
+    pub nonces: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEntry {
     path: String,
     size: usize,
+    #[serde(default, rename = "type", skip_serializing_if = "Kind::is_file")]
+    kind: Kind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<Checksum>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pack: Option<Pack>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<Encryption>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime_nsec: Option<i64>,
 }
 
 impl FileEntry {
-    pub async unsafe fn open(&self) -> Result<mmap::Handle, Error> {
+    pub(crate) async unsafe fn open(&self, dir: &Path) -> Result<mmap::Handle, Error> {
         let file = fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(false)
-            .open(&self.path)
+            .open(dir.join(&self.path))
             .await?;
         let handle = mmap::Handle::new(file, self.size)?;
         Ok(handle)
     }
 
-    pub async fn create(&self) -> Result<mmap::Handle, Error> {
-        if let Some(dir) = Path::new(&self.path).parent() {
+    pub(crate) async fn create(&self, dir: &Path) -> Result<mmap::Handle, Error> {
+        let path = dir.join(&self.path);
+        if let Some(dir) = path.parent() {
             fs::create_dir_all(dir).await?;
         }
         let mut file = fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create_new(true)
-            .open(&self.path)
+            .open(&path)
             .await?;
-        if self.size > 0 {
-            file.seek(SeekFrom::Start(self.size as u64 - 1)).await?;
-            file.write(&[0]).await?;
+        // the file must have the size before mmap, which the writes of tokio don't wait for
+        file.set_len(self.size as u64).await?;
+        let handle = unsafe { mmap::Handle::new(file, self.size) }?;
+        Ok(handle)
+    }
+
+    pub(crate) async fn reopen(&self, dir: &Path) -> Result<mmap::Handle, Error> {
+        let path = dir.join(&self.path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
         }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+        file.set_len(self.size as u64).await?;
         let handle = unsafe { mmap::Handle::new(file, self.size) }?;
         Ok(handle)
     }
 
+    /// If `replace` is set, the link left by an interrupted run is replaced.
+    pub(crate) async fn create_node(&self, dir: &Path, replace: bool) -> Result<(), Error> {
+        let path = dir.join(&self.path);
+        if self.kind == Kind::Directory {
+            fs::create_dir_all(&path).await?;
+            return Ok(());
+        }
+        let link = self.link.as_ref().ok_or("no link target in manifest")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        if replace {
+            match fs::remove_file(&path).await {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        match self.kind {
+            Kind::Symlink => fs::os::unix::symlink(link, &path).await?,
+            // the target of the hard link is the path in the archive too
+            Kind::HardLink => fs::hard_link(dir.join(link), &path).await?,
+            Kind::File | Kind::Directory => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// The setuid and setgid bits are restored only with the ownership.
+    /// Symlinks themselves are modified instead of their targets.
+    pub(crate) async fn restore_metadata(&self, dir: &Path, same_owner: bool) -> Result<(), Error> {
+        if self.kind == Kind::HardLink {
+            // shares the inode with the linked file entry
+            return Ok(());
+        }
+        let path = dir.join(&self.path);
+        if same_owner && (self.uid.is_some() || self.gid.is_some()) {
+            let uid = self.uid.map(Uid::from_raw);
+            let gid = self.gid.map(Gid::from_raw);
+            let flag = FchownatFlags::NoFollowSymlink;
+            fchownat(None, &path, uid, gid, flag)?;
+        }
+        if let Some(mode) = self.mode.filter(|_| self.kind != Kind::Symlink) {
+            let mode = if same_owner { mode } else { mode & 0o1777 };
+            fs::set_permissions(&path, Permissions::from_mode(mode)).await?;
+        }
+        if let Some(mtime) = self.mtime {
+            let mtime =
+                TimeSpec::seconds(mtime) + TimeSpec::nanoseconds(self.mtime_nsec.unwrap_or(0));
+            utimensat(None, &path, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+        }
+        Ok(())
+    }
+
     pub fn new(path: String, size: usize) -> FileEntry {
-        FileEntry { path, size }
+        FileEntry {
+            path,
+            size,
+            kind: Kind::File,
+            link: None,
+            checksum: None,
+            pack: None,
+            compression: None,
+            encryption: None,
+            mode: None,
+            uid: None,
+            gid: None,
+            mtime: None,
+            mtime_nsec: None,
+        }
+    }
+
+    pub fn with_metadata(path: String, metadata: &Metadata) -> FileEntry {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_dir() {
+            Kind::Directory
+        } else if file_type.is_symlink() {
+            Kind::Symlink
+        } else {
+            Kind::File
+        };
+        let size = if kind == Kind::File {
+            metadata.len() as usize
+        } else {
+            0
+        };
+        FileEntry {
+            path,
+            size,
+            kind,
+            link: None,
+            checksum: None,
+            pack: None,
+            compression: None,
+            encryption: None,
+            mode: Some(metadata.mode() & 0o7777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            mtime: Some(metadata.mtime()),
+            mtime_nsec: Some(metadata.mtime_nsec()),
+        }
+    }
+
+    pub fn symlink_to(self, target: String) -> FileEntry {
+        FileEntry {
+            size: 0,
+            kind: Kind::Symlink,
+            link: Some(target),
+            ..self
+        }
+    }
+
+    pub fn hard_link_to(self, path: String) -> FileEntry {
+        FileEntry {
+            size: 0,
+            kind: Kind::HardLink,
+            link: Some(path),
+            ..self
+        }
+    }
+
+    pub fn with_checksum(self, checksum: Checksum) -> FileEntry {
+        FileEntry {
+            checksum: Some(checksum),
+            ..self
+        }
+    }
+
+    pub fn packed_in(self, pack: Pack) -> FileEntry {
+        FileEntry {
+            pack: Some(pack),
+            ..self
+        }
+    }
+
+    pub fn compressed_with(self, compression: Compression) -> FileEntry {
+        FileEntry {
+            compression: Some(compression),
+            ..self
+        }
+    }
+
+    pub fn encrypted_with(self, encryption: Encryption) -> FileEntry {
+        FileEntry {
+            encryption: Some(encryption),
+            ..self
+        }
+    }
+
+    /// The object copied with other parts, e.g. by `aws s3 cp`, is verified as a whole instead
+    pub(crate) fn verifies_parts(&self, parts_count: i64) -> bool {
+        match self.checksum {
+            Some(ref checksum) => checksum.parts.len() as i64 == parts_count,
+            None => false,
+        }
+    }
+
+    pub(crate) fn verify_part(&self, part_number: i64, data: &[u8]) -> Result<(), Error> {
+        let checksum = self.checksum.as_ref();
+        let expected = match checksum.and_then(|c| c.parts.get(part_number as usize - 1)) {
+            Some(&expected) => expected,
+            None => return Ok(()),
+        };
+        let actual = crc32c::crc32c(data);
+        if expected != actual {
+            return Err(ChecksumError {
+                path: self.path.clone(),
+                part_number: Some(part_number),
+                expected,
+                actual,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Verifies the whole downloaded file against the checksum in the manifest if any
+    ///
+    /// It's for the file which isn't downloaded by the parts recorded in the manifest.
+    pub(crate) async fn verify_file(&self, dir: &Path) -> Result<(), Error> {
+        let expected = match self.checksum {
+            Some(ref checksum) => checksum.crc32c,
+            None => return Ok(()),
+        };
+        let path = dir.join(&self.path);
+        let actual = task::spawn_blocking(move || crc32c_of_file(&path)).await??;
+        if actual != expected {
+            return Err(ChecksumError {
+                path: self.path.clone(),
+                part_number: None,
+                expected,
+                actual,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
     }
 
     pub fn path(&self) -> &str {
@@ -54,4 +374,48 @@ impl FileEntry {
     pub fn size(&self) -> usize {
         self.size
     }
+
+    pub fn link(&self) -> Option<&str> {
+        self.link.as_deref()
+    }
+
+    pub fn checksum(&self) -> Option<&Checksum> {
+        self.checksum.as_ref()
+    }
+
+    pub fn pack(&self) -> Option<&Pack> {
+        self.pack.as_ref()
+    }
+
+    pub fn compression(&self) -> Option<&Compression> {
+        self.compression.as_ref()
+    }
+
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    pub fn object_size(&self) -> usize {
+        match (&self.compression, &self.encryption) {
+            (Some(compression), _) => compression.compressed_size(),
+            (None, Some(encryption)) => self.size + encryption.nonces.len() * TAG_SIZE,
+            (None, None) => self.size,
+        }
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    pub fn mtime(&self) -> Option<i64> {
+        self.mtime
+    }
 }
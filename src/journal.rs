@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::fs;
+use tokio::prelude::*;
+use tokio::sync::Mutex;
+
+use super::error::Error;
+
+/// Records which parts of which files have been downloaded
+/// so that an interrupted download can be resumed.
+///
+/// Each line is `part_number\tparts_count\tlength\tpath`,
+/// or `ranges\trange_size\tpath` before the parts of the file downloaded by ranges.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Option<Mutex<fs::File>>,
+    completed: HashMap<String, Arc<CompletedParts>>,
+    range_sizes: HashMap<String, usize>,
+}
+
+pub type CompletedParts = HashMap<i64, CompletedPart>;
+
+#[derive(Debug, Clone)]
+pub struct CompletedPart {
+    pub parts_count: i64,
+    pub len: usize,
+}
+
+impl Journal {
+    pub async fn open(path: PathBuf, resume: bool) -> Result<Self, Error> {
+        if !resume {
+            return Ok(Journal {
+                path,
+                file: None,
+                completed: HashMap::new(),
+                range_sizes: HashMap::new(),
+            });
+        }
+        let mut completed = HashMap::<String, CompletedParts>::new();
+        let mut range_sizes = HashMap::new();
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        // the last line may be torn by a crash,
+        // so only the lines terminated by a newline are trusted
+        let torn = !content.is_empty() && !content.ends_with('\n');
+        let content = content.rfind('\n').map(|i| &content[..i]).unwrap_or("");
+        for line in content.lines() {
+            if let Some((range_size, file_path)) = parse_ranges_line(line) {
+                range_sizes.insert(file_path.to_string(), range_size);
+            } else if let Some((part_number, part, file_path)) = parse_line(line) {
+                completed
+                    .entry(file_path.to_string())
+                    .or_default()
+                    .insert(part_number, part);
+            }
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        if torn {
+            file.write_all(b"\n").await?;
+        }
+        let completed = completed
+            .into_iter()
+            .map(|(file_path, parts)| (file_path, Arc::new(parts)))
+            .collect();
+        Ok(Journal {
+            path,
+            file: Some(Mutex::new(file)),
+            completed,
+            range_sizes,
+        })
+    }
+
+    pub fn completed_parts(&self, file_path: &str) -> Arc<CompletedParts> {
+        self.completed.get(file_path).cloned().unwrap_or_default()
+    }
+
+    pub fn range_size(&self, file_path: &str) -> Option<usize> {
+        self.range_sizes.get(file_path).cloned()
+    }
+
+    pub fn is_completed(&self, file_path: &str) -> bool {
+        let parts = match self.completed.get(file_path) {
+            Some(parts) => parts,
+            None => return false,
+        };
+        parts
+            .values()
+            .next()
+            .map(|part| (1..=part.parts_count).all(|n| parts.contains_key(&n)))
+            .unwrap_or(false)
+    }
+
+    pub async fn complete_part(
+        &self,
+        file_path: &str,
+        part_number: i64,
+        part: CompletedPart,
+    ) -> Result<(), Error> {
+        let line = format!(
+            "{}\t{}\t{}\t{}\n",
+            part_number, part.parts_count, part.len, file_path
+        );
+        self.write_line(&line).await
+    }
+
+    pub async fn start_ranges(&self, file_path: &str, range_size: usize) -> Result<(), Error> {
+        let line = format!("ranges\t{}\t{}\n", range_size, file_path);
+        self.write_line(&line).await
+    }
+
+    async fn write_line(&self, line: &str) -> Result<(), Error> {
+        let mut file = match &self.file {
+            Some(file) => file.lock().await,
+            None => return Ok(()),
+        };
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+
+    pub async fn remove(&self) -> Result<(), Error> {
+        if self.file.is_some() {
+            fs::remove_file(&self.path).await?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_line(line: &str) -> Option<(i64, CompletedPart, &str)> {
+    let mut cols = line.splitn(4, '\t');
+    let part_number = cols.next()?.parse().ok()?;
+    let parts_count = cols.next()?.parse().ok()?;
+    let len = cols.next()?.parse().ok()?;
+    let file_path = cols.next()?;
+    Some((part_number, CompletedPart { parts_count, len }, file_path))
+}
+
+fn parse_ranges_line(line: &str) -> Option<(usize, &str)> {
+    let mut cols = line.splitn(3, '\t');
+    if cols.next()? != "ranges" {
+        return None;
+    }
+    let range_size = cols.next()?.parse().ok()?;
+    let file_path = cols.next()?;
+    Some((range_size, file_path))
+}
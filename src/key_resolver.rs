@@ -1,7 +1,41 @@
-pub fn data_key(s3_prefix: &str, path: &str) -> String {
-    format!("{}data/{}", s3_prefix, path)
+use super::encryption::DataKey;
+use super::file_entry::{FileEntry, Kind};
+
+/// The paths are hidden in the keys of the encrypted archive.
+pub fn data_key(s3_prefix: &str, path: &str, data_key: Option<&DataKey>) -> String {
+    match data_key {
+        Some(data_key) => format!("{}{}", data_prefix(s3_prefix), data_key.object_name(path)),
+        None => format!("{}{}", data_prefix(s3_prefix), path),
+    }
+}
+
+/// The prefix of the data keys, which are the only ones uploaded by multipart uploads
+pub fn data_prefix(s3_prefix: &str) -> String {
+    format!("{}data/", s3_prefix)
 }
 
 pub fn manifest_key(s3_prefix: &str) -> String {
     format!("{}manifest", s3_prefix)
 }
+
+pub fn pack_key(s3_prefix: &str, pack: &str) -> String {
+    format!("{}{}", s3_prefix, pack)
+}
+
+pub fn pack_name(index: usize) -> String {
+    format!("packs/{:08}", index)
+}
+
+pub fn object_key(
+    s3_prefix: &str,
+    entry: &FileEntry,
+    data_key: Option<&DataKey>,
+) -> Option<String> {
+    match entry.pack() {
+        Some(pack) => Some(pack_key(s3_prefix, &pack.key)),
+        None if entry.kind() == Kind::File => {
+            Some(self::data_key(s3_prefix, entry.path(), data_key))
+        }
+        None => None,
+    }
+}
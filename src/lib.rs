@@ -0,0 +1,71 @@
+//! Massively fast S3 downloader/uploader
+//!
+//! The archive is uploaded by [`CreateExecutor`] and downloaded by [`ExtractExecutor`]
+//! with the store of the objects, e.g. [`store::S3Store`].
+//! The futures of the S3 client need the runtime which can also run futures 0.1,
+//! such as the one of tokio-compat.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use s3ar::store::S3Store;
+//! use s3ar::{ArchiveCreate, CreateExecutor, Progress};
+//!
+//! # async fn upload() -> Result<(), s3ar::Error> {
+//! let store = Arc::new(S3Store::new(rusoto_s3::S3Client::new(Default::default())));
+//! let (canceller, cancellation) = s3ar::cancellation();
+//! let creator = CreateExecutor::new(store)
+//!     .on_progress(Arc::new(|progress: &Progress| {
+//!         if let Progress::FileCompleted { path } = progress {
+//!             println!("uploaded {}", path);
+//!         }
+//!     }))
+//!     .with_cancellation(cancellation);
+//! let archive = ArchiveCreate {
+//!     s3_bucket: "bucket".to_string(),
+//!     s3_prefix: "prefix".to_string(),
+//!     files: vec!["dir".into()],
+//!     ..Default::default()
+//! };
+//! creator.execute(archive).await?;
+//! # drop(canceller);
+//! # Ok(())
+//! # }
+//! ```
+
+mod cancel;
+mod chan_exec;
+pub mod cleanup;
+pub mod compression;
+pub mod create;
+pub mod encryption;
+pub mod error;
+pub mod extract;
+pub mod file_entry;
+mod journal;
+mod key_resolver;
+pub mod list;
+pub mod manifest;
+pub mod meter;
+mod mmap;
+pub mod progress;
+pub mod report;
+pub mod restore;
+pub mod store;
+#[cfg(test)]
+mod testing;
+mod utils;
+pub mod verify;
+
+pub use cancel::{cancellation, Cancellation, Canceller};
+pub use compression::{Codec, Compressor};
+pub use create::{ArchiveCreate, CreateExecutor};
+pub use encryption::{KeyProvider, MasterKeyProvider};
+pub use error::{Error, ErrorKind};
+pub use extract::{ArchiveExtract, ExtractExecutor};
+pub use file_entry::{Compression, Encryption, FileEntry, Kind, Pack};
+pub use manifest::{Header, Reader as ManifestReader, Writer as ManifestWriter};
+pub use meter::ProgressMeter;
+pub use progress::{Progress, ProgressCallback};
+pub use report::{EventLog, Report};
+pub use restore::{ArchiveRestore, RestoreExecutor};
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::prelude::*;
+use serde::Serialize;
+
+use super::encryption::KeyProvider;
+use super::file_entry::{FileEntry, Kind};
+use super::manifest;
+use super::store::ObjectStore;
+use super::utils::build_glob_set;
+use super::Error;
+
+#[derive(Debug, Clone)]
+pub struct ArchiveList {
+    pub s3_bucket: String,
+    pub s3_prefix: String,
+    pub patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Total {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+pub type EntryCallback = Arc<dyn Fn(&FileEntry) + Send + Sync>;
+
+pub struct ListExecutor {
+    store: Arc<dyn ObjectStore>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    on_entry: Option<EntryCallback>,
+}
+
+impl ListExecutor {
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            key_provider: None,
+            on_entry: None,
+        }
+    }
+
+    pub fn on_entry(mut self, callback: EntryCallback) -> Self {
+        self.on_entry = Some(callback);
+        self
+    }
+
+    pub fn with_key_provider(mut self, key_provider: Arc<dyn KeyProvider>) -> Self {
+        self.key_provider = Some(key_provider);
+        self
+    }
+
+    pub async fn execute(
+        &self,
+        ArchiveList {
+            s3_bucket,
+            s3_prefix,
+            patterns,
+        }: ArchiveList,
+    ) -> Result<Total, Error> {
+        let patterns = build_glob_set(&patterns)?;
+        let key_provider = self.key_provider.as_deref();
+        let (_, entries) =
+            manifest::Reader::get(self.store.as_ref(), &s3_bucket, &s3_prefix, key_provider)
+                .await?
+                .into_parts();
+        entries
+            .try_filter(|entry| {
+                future::ready(patterns.is_empty() || patterns.is_match(entry.path()))
+            })
+            .try_fold(Total::default(), |total, entry| {
+                if let Some(callback) = &self.on_entry {
+                    callback(&entry);
+                }
+                future::ok(Total {
+                    entries: total.entries + 1,
+                    bytes: total.bytes + entry.size() as u64,
+                })
+            })
+            .await
+    }
+}
+
+pub fn format_long(entry: &FileEntry) -> String {
+    let owner = match (entry.uid(), entry.gid()) {
+        (Some(uid), Some(gid)) => format!("{}/{}", uid, gid),
+        _ => "-".to_string(),
+    };
+    let mtime = match entry.mtime() {
+        Some(mtime) if mtime >= 0 => {
+            let mtime = UNIX_EPOCH + Duration::from_secs(mtime as u64);
+            humantime::format_rfc3339_seconds(mtime).to_string()
+        }
+        _ => "-".to_string(),
+    };
+    let link = match (entry.kind(), entry.link()) {
+        (Kind::Symlink, Some(link)) => format!(" -> {}", link),
+        (Kind::HardLink, Some(link)) => format!(" link to {}", link),
+        _ => String::new(),
+    };
+    format!(
+        "{} {} {:>12} {} {}{}",
+        format_mode(entry),
+        owner,
+        entry.size(),
+        mtime,
+        entry.path(),
+        link
+    )
+}
+
+fn format_mode(entry: &FileEntry) -> String {
+    let kind = match entry.kind() {
+        Kind::File => '-',
+        Kind::Directory => 'd',
+        Kind::Symlink => 'l',
+        Kind::HardLink => 'h',
+    };
+    let mode = match entry.mode() {
+        Some(mode) => mode,
+        None => return format!("{}?????????", kind),
+    };
+    let mut formatted = String::with_capacity(10);
+    formatted.push(kind);
+    for (i, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = mode >> i;
+        formatted.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        formatted.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        formatted.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    formatted
+}
@@ -1,26 +1,42 @@
 use tokio_compat::runtime;
 
 use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::process;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use rusoto_core::Region;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 
-mod chan_exec;
-mod create;
-mod error;
-mod extract;
-mod file_entry;
-mod key_resolver;
-mod mmap;
-mod utils;
+use s3ar::store::{self, ObjectStore, ServerSideEncryption};
+use s3ar::{
+    cleanup, create, extract, list, restore, verify, Error, ErrorKind, EventLog, KeyProvider,
+    MasterKeyProvider, Progress, ProgressCallback, ProgressMeter, Report,
+};
 
-use error::Error;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_PERMISSION_DENIED: i32 = 3;
+const EXIT_NOT_FOUND: i32 = 4;
+const EXIT_INTEGRITY: i32 = 5;
+const EXIT_PARTIAL: i32 = 6;
 
-fn args() -> ArgMatches<'static> {
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    1    Failed by the others, e.g. the network or an internal error
+    2    Invalid arguments or options
+    3    Missing credentials or denied access
+    4    Missing archive, object or file
+    5    Data not matching the checksum or the archive
+    6    Some files were transferred and others failed, which --resume continues";
+
+fn args() -> Result<ArgMatches<'static>, clap::Error> {
     App::new("s3ar")
         .about("Massively fast S3 downloader/uploader")
+        .after_help(EXIT_CODES_HELP)
         .arg(
             Arg::with_name("directory")
                 .short("C")
@@ -29,6 +45,82 @@ fn args() -> ArgMatches<'static> {
                 .help("Sets the current directory")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("endpoint_url")
+                .long("endpoint-url")
+                .value_name("URL")
+                .help("Sets the endpoint of S3 [env: AWS_ENDPOINT_URL_S3, AWS_ENDPOINT_URL]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("local_root")
+                .long("local-root")
+                .value_name("DIR")
+                .help("Stores archives in DIR/BUCKET/KEY instead of S3 [env: S3AR_LOCAL_ROOT]")
+                .takes_value(true)
+                .conflicts_with("endpoint_url"),
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Writes the events to stderr in JSON lines if json")
+                .takes_value(true)
+                .possible_values(&["text", "json"]),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .value_name("FILE")
+                .help("Writes the summary of the transfer to the file in JSON")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Doesn't show the progress of uploads and downloads"),
+        )
+        .arg(
+            Arg::with_name("key_file")
+                .long("key-file")
+                .value_name("FILE")
+                .help("Sets the file of the master key of encrypted archives in 32 bytes or base64")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key_env")
+                .long("key-env")
+                .value_name("VAR")
+                .help("Sets the environment variable of the master key of encrypted archives in base64")
+                .takes_value(true)
+                .conflicts_with("key_file"),
+        )
+        .arg(
+            Arg::with_name("sse")
+                .long("sse")
+                .value_name("ALGORITHM")
+                .help("Encrypts the uploaded objects on S3 by SSE-S3 or SSE-KMS")
+                .takes_value(true)
+                .possible_values(&["AES256", "aws:kms"])
+                .conflicts_with("local_root"),
+        )
+        .arg(
+            Arg::with_name("sse_kms_key_id")
+                .long("sse-kms-key-id")
+                .value_name("KEY_ID")
+                .help("Sets the KMS key of SSE-KMS, which implies --sse aws:kms")
+                .takes_value(true)
+                .conflicts_with("local_root"),
+        )
+        .arg(
+            Arg::with_name("sse_c_key_file")
+                .long("sse-c-key-file")
+                .value_name("FILE")
+                .help("Sets the file of the SSE-C customer key in 32 bytes or base64")
+                .takes_value(true)
+                .conflicts_with_all(&["sse", "sse_kms_key_id", "local_root"]),
+        )
         .subcommand(
             SubCommand::with_name("upload")
                 .arg(
@@ -63,6 +155,111 @@ fn args() -> ArgMatches<'static> {
                         .help("Sets the part size in bytes")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("put_threshold")
+                        .long("put-threshold")
+                        .value_name("SIZE")
+                        .help("Uploads the files up to SIZE bytes by PutObject instead of multipart upload")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pack_threshold")
+                        .long("pack-threshold")
+                        .value_name("SIZE")
+                        .help("Packs the files smaller than SIZE bytes into pack objects")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pack_size")
+                        .long("pack-size")
+                        .value_name("SIZE")
+                        .help("Sets the size of pack objects in bytes")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compress")
+                        .long("compress")
+                        .value_name("CODEC[:LEVEL]")
+                        .help("Compresses the files by zstd or gzip unless they are compressed already")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("encrypt")
+                        .long("encrypt")
+                        .help("Encrypts the archive by a data key wrapped by the master key"),
+                )
+                .arg(
+                    Arg::with_name("storage_class")
+                        .long("storage-class")
+                        .value_name("CLASS")
+                        .help("Sets the storage class of the objects, except GLACIER and DEEP_ARCHIVE for the manifest")
+                        .takes_value(true)
+                        .possible_values(&[
+                            "STANDARD",
+                            "REDUCED_REDUNDANCY",
+                            "STANDARD_IA",
+                            "ONEZONE_IA",
+                            "INTELLIGENT_TIERING",
+                            "GLACIER",
+                            "GLACIER_IR",
+                            "DEEP_ARCHIVE",
+                        ]),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .value_name("KEY=VALUE")
+                        .help("Tags the objects")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("metadata")
+                        .long("metadata")
+                        .value_name("KEY=VALUE")
+                        .help("Sets the user metadata of the objects")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .help("Resumes interrupted uploads instead of starting over"),
+                )
+                .arg(
+                    Arg::with_name("follow_symlinks")
+                        .long("follow-symlinks")
+                        .help("Archives the targets of symlinks instead of the symlinks"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .value_name("PATTERN")
+                        .help("Skips the files matching the pattern in gitignore syntax")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("exclude_from")
+                        .long("exclude-from")
+                        .value_name("FILE")
+                        .help("Skips the files matching the patterns in the file")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .value_name("PATTERN")
+                        .help("Archives only the files matching the pattern in gitignore syntax")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
                 .arg(
                     Arg::with_name("TARGET_BUCKET")
                         .help("Sets the S3 bucket")
@@ -101,51 +298,500 @@ fn args() -> ArgMatches<'static> {
                         .help("Sets the concurrency of parts")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .help("Journals completed parts and resumes an interrupted download"),
+                )
+                .arg(
+                    Arg::with_name("same_owner")
+                        .long("same-owner")
+                        .help("Restores the ownership and the setuid/setgid bits of files"),
+                )
+                .arg(
+                    Arg::with_name("journal")
+                        .long("journal")
+                        .value_name("FILE")
+                        .help("Sets the journal file of completed parts")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("range_size")
+                        .long("range-size")
+                        .value_name("SIZE")
+                        .help("Sets the size in bytes of ranged GETs for objects uploaded without multipart")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("SOURCE_BUCKET")
                         .help("Sets the S3 bucket")
                         .required(true)
                         .index(1),
                 )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .value_name("GLOB")
+                        .help("Extracts only the files matching the pattern")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .value_name("GLOB")
+                        .help("Skips the files matching the pattern")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("wait_for_restore")
+                        .long("wait-for-restore")
+                        .help("Waits for the archived objects to be restored before extracting"),
+                )
+                .arg(
+                    Arg::with_name("restore_poll_interval")
+                        .long("restore-poll-interval")
+                        .value_name("DURATION")
+                        .help("Sets the interval of checking the restored objects (default: 5m)")
+                        .takes_value(true)
+                        .requires("wait_for_restore"),
+                )
                 .arg(
                     Arg::with_name("SOURCE_PREFIX")
                         .help("Sets the S3 prefix")
                         .required(true)
                         .index(2),
                 )
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Extracts only the files and the directories at the paths")
+                        .index(3)
+                        .multiple(true),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("cleanup")
+                .about("Aborts incomplete multipart uploads under the S3 prefix")
+                .arg(
+                    Arg::with_name("older_than")
+                        .long("older-than")
+                        .value_name("DURATION")
+                        .help("Aborts only the uploads older than the duration (default: 24h)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Lists the uploads without aborting them"),
+                )
+                .arg(
+                    Arg::with_name("BUCKET")
+                        .help("Sets the S3 bucket")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("Sets the S3 prefix")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Compares the archive with the local files")
+                .arg(
+                    Arg::with_name("file_concurrency")
+                        .short("F")
+                        .long("file-concurrency")
+                        .value_name("NUM")
+                        .help("Sets the concurrency of files")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("BUCKET")
+                        .help("Sets the S3 bucket")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("Sets the S3 prefix")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restores the archived objects of the archive, printing the ones not restored yet")
+                .arg(
+                    Arg::with_name("file_concurrency")
+                        .short("F")
+                        .long("file-concurrency")
+                        .value_name("NUM")
+                        .help("Sets the concurrency of objects")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tier")
+                        .long("tier")
+                        .value_name("TIER")
+                        .help("Sets the tier of restoring the objects")
+                        .takes_value(true)
+                        .possible_values(&["Bulk", "Standard", "Expedited"]),
+                )
+                .arg(
+                    Arg::with_name("days")
+                        .long("days")
+                        .value_name("NUM")
+                        .help("Keeps the restored copies for the days")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("BUCKET")
+                        .help("Sets the S3 bucket")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("Sets the S3 prefix")
+                        .required(true)
+                        .index(2),
+                ),
         )
-        .get_matches()
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the files in the archive")
+                .arg(
+                    Arg::with_name("long")
+                        .short("l")
+                        .long("long")
+                        .help("Lists the mode, the owner and the mtime too"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Lists the entries of the manifest and the total in JSON lines")
+                        .conflicts_with("long"),
+                )
+                .arg(
+                    Arg::with_name("BUCKET")
+                        .help("Sets the S3 bucket")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("Sets the S3 prefix")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("PATTERN")
+                        .help("Lists only the paths matching any of the glob patterns")
+                        .index(3)
+                        .multiple(true),
+                ),
+        )
+        .get_matches_safe()
 }
 
 fn main() {
-    let aws_region = env::var("AWS_REGION")
-        .map(|v| v.parse())
-        .unwrap_or(Ok(Region::ApNortheast1))
-        .expect("failed to parse AWS_REGION");
+    let matches = match args() {
+        Ok(matches) => matches,
+        // the help and the version are printed to stdout as succeeded
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e.message);
+            process::exit(EXIT_USAGE);
+        }
+    };
 
-    let matches = args();
+    let (command, _) = matches.subcommand();
+    let log = EventLog::new(command, is_json_log(&matches));
+    let mut result = run(&matches, &log);
+    let report = log.finish(result.as_ref().err());
+    if let Some(report_file) = matches.value_of_os("report") {
+        let written = report
+            .write(report_file)
+            .map_err(|e| e.context("failed to write report"));
+        // the failure of the command is told rather than the one of the report
+        result = result.and(written);
+    }
+    if let Err(e) = result {
+        // the log in JSON has the error already
+        if !is_json_log(&matches) {
+            print_error(&e);
+        }
+        process::exit(exit_code(&e, &report));
+    }
+}
+
+fn run(matches: &ArgMatches, log: &EventLog) -> Result<(), Error> {
+    let aws_region = build_region(matches)?;
 
     let mut rt = runtime::Builder::default()
         .core_threads(4)
         .build()
-        .expect("failed to create Runtime");
+        .map_err(|e| Error::from(e).context("failed to create Runtime"))?;
+
+    let store = build_store(matches, aws_region)?;
+    let key_provider = build_key_provider(matches)?;
+    execute(&mut rt, matches, store, key_provider, log)
+}
 
-    let s3_client = rusoto_s3::S3Client::new(aws_region);
+fn execute(
+    rt: &mut runtime::Runtime,
+    matches: &ArgMatches,
+    store: Arc<dyn ObjectStore>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    log: &EventLog,
+) -> Result<(), Error> {
     if let Some(sub_matches) = matches.subcommand_matches("upload") {
-        let creator = create::CreateExecutor::new(s3_client);
-        let fut = creator.execute(build_archive_create(&matches, &sub_matches));
-        rt.block_on_std(fut).expect("failed to execute");
-        return;
+        let meter = build_meter(matches);
+        let callback = progress_callback(matches, meter.as_ref(), log);
+        let mut creator = create::CreateExecutor::new(store).on_progress(callback);
+        if sub_matches.is_present("encrypt") {
+            let key_provider = key_provider
+                .ok_or_else(|| Error::invalid_input("--encrypt needs --key-file or --key-env"))?;
+            creator = creator.with_key_provider(key_provider);
+        }
+        let archive = build_archive_create(matches, sub_matches)?;
+        log.start(&archive.s3_bucket, &archive.s3_prefix);
+        return rt.block_on_std(creator.execute(archive));
     }
     if let Some(sub_matches) = matches.subcommand_matches("download") {
-        let extractor = extract::ExtractExecutor::new(s3_client);
-        let fut = extractor.execute(build_archive_extract(&matches, &sub_matches));
-        rt.block_on_std(fut).expect("failed to execute");
-        return;
+        let meter = build_meter(matches);
+        let callback = progress_callback(matches, meter.as_ref(), log);
+        let mut extractor = extract::ExtractExecutor::new(store).on_progress(callback);
+        if let Some(key_provider) = key_provider {
+            extractor = extractor.with_key_provider(key_provider);
+        }
+        let archive = build_archive_extract(matches, sub_matches)?;
+        log.start(&archive.s3_bucket, &archive.s3_prefix);
+        return rt.block_on_std(extractor.execute(archive));
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("cleanup") {
+        let cleaner = cleanup::CleanupExecutor::new(store).on_upload(Arc::new(|upload| {
+            println!("{}\t{}\t{}", upload.initiated, upload.key, upload.upload_id)
+        }));
+        let archive = build_archive_cleanup(sub_matches)?;
+        log.start(&archive.s3_bucket, &archive.s3_prefix);
+        return rt.block_on_std(cleaner.execute(archive));
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("verify") {
+        let mut verifier = verify::VerifyExecutor::new(store)
+            .on_mismatch(Arc::new(|mismatch| println!("{}", mismatch)));
+        if let Some(key_provider) = key_provider {
+            verifier = verifier.with_key_provider(key_provider);
+        }
+        let archive = build_archive_verify(matches, sub_matches)?;
+        log.start(&archive.s3_bucket, &archive.s3_prefix);
+        return rt.block_on_std(verifier.execute(archive));
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("restore") {
+        let mut restorer = restore::RestoreExecutor::new(store).on_state(Arc::new(|key, state| {
+            if state != restore::State::Available {
+                println!("{}\t{}", state, key);
+            }
+        }));
+        if let Some(key_provider) = key_provider {
+            restorer = restorer.with_key_provider(key_provider);
+        }
+        let archive = build_archive_restore(sub_matches)?;
+        log.start(&archive.s3_bucket, &archive.s3_prefix);
+        let total = rt.block_on_std(restorer.execute(archive))?;
+        eprintln!(
+            "total: {} available, {} restoring, {} requested",
+            total.available, total.restoring, total.requested
+        );
+        if total.missing > 0 {
+            let message = format!("{} objects missing", total.missing);
+            return Err(Error::not_found(message));
+        }
+        return Ok(());
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("list") {
+        let long = sub_matches.is_present("long");
+        let json = sub_matches.is_present("json");
+        let mut lister = list::ListExecutor::new(store).on_entry(Arc::new(move |entry| {
+            if json {
+                // the entries were read from JSON, so they can't fail to be written back
+                let line = serde_json::to_string(entry).expect("failed to serialize entry");
+                println!("{}", line);
+            } else if long {
+                println!("{}", list::format_long(entry));
+            } else {
+                println!("{}\t{}", entry.size(), entry.path());
+            }
+        }));
+        if let Some(key_provider) = key_provider {
+            lister = lister.with_key_provider(key_provider);
+        }
+        let archive = build_archive_list(sub_matches);
+        log.start(&archive.s3_bucket, &archive.s3_prefix);
+        let total = rt.block_on_std(lister.execute(archive))?;
+        if json {
+            println!("{}", serde_json::json!({ "total": total }));
+        } else {
+            println!("total: {} entries, {} bytes", total.entries, total.bytes);
+        }
+        return Ok(());
+    }
+    Ok(())
+}
+
+/// Tells the most specific reason of the failure,
+/// or the partial failure which the files transferred so far are resumed from
+fn exit_code(error: &Error, report: &Report) -> i32 {
+    match error.kind() {
+        ErrorKind::InvalidInput => EXIT_USAGE,
+        ErrorKind::PermissionDenied => EXIT_PERMISSION_DENIED,
+        ErrorKind::NotFound => EXIT_NOT_FOUND,
+        ErrorKind::Integrity => EXIT_INTEGRITY,
+        _ if report.files > 0 && report.failed > 0 => EXIT_PARTIAL,
+        _ => EXIT_FAILURE,
+    }
+}
+
+fn print_error(error: &Error) {
+    let mut messages = error.chain().into_iter();
+    if let Some(message) = messages.next() {
+        eprintln!("error: {}", message);
     }
+    for cause in messages {
+        eprintln!("  caused by: {}", cause);
+    }
+}
+
+fn is_json_log(matches: &ArgMatches) -> bool {
+    matches.value_of("log_format") == Some("json")
+}
+
+/// The meter is left out if quiet or the log is in JSON, which takes stderr
+fn build_meter(matches: &ArgMatches) -> Option<ProgressMeter> {
+    if matches.is_present("quiet") || is_json_log(matches) {
+        return None;
+    }
+    Some(ProgressMeter::start())
+}
+
+fn progress_callback(
+    matches: &ArgMatches,
+    meter: Option<&ProgressMeter>,
+    log: &EventLog,
+) -> ProgressCallback {
+    let notices = !is_json_log(matches);
+    let meter_callback = meter.map(ProgressMeter::callback);
+    let log_callback = log.callback();
+    Arc::new(move |progress: &Progress| {
+        log_callback(progress);
+        if notices {
+            print_notice(progress);
+        }
+        if let Some(meter_callback) = &meter_callback {
+            meter_callback(progress);
+        }
+    })
+}
+
+fn print_notice(progress: &Progress) {
+    match progress {
+        Progress::Skipped { path, reason } => eprintln!("skipping {}: {}", path, reason),
+        Progress::Restoring { pending } => {
+            eprintln!("waiting for {} objects to be restored", pending)
+        }
+        _ => {}
+    }
+}
+
+fn build_region(matches: &ArgMatches) -> Result<Region, Error> {
+    let aws_region = env::var("AWS_REGION").ok();
+
+    let endpoint = matches
+        .value_of("endpoint_url")
+        .map(Into::into)
+        .or_else(|| env::var("AWS_ENDPOINT_URL_S3").ok())
+        .or_else(|| env::var("AWS_ENDPOINT_URL").ok());
+    if let Some(endpoint) = endpoint {
+        // S3 compatible storages may have their own region names,
+        // and the buckets are addressed by the path as they require
+        let name = aws_region.unwrap_or_else(|| Region::ApNortheast1.name().to_string());
+        return Ok(Region::Custom {
+            name,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+        });
+    }
+    aws_region
+        .map(|v| v.parse())
+        .unwrap_or(Ok(Region::ApNortheast1))
+        .map_err(|e| parse_error("AWS_REGION", e))
 }
 
-fn build_archive_create(matches: &ArgMatches, sub_matches: &ArgMatches) -> create::ArchiveCreate {
+fn build_store(matches: &ArgMatches, aws_region: Region) -> Result<Arc<dyn ObjectStore>, Error> {
+    let local_root = matches
+        .value_of_os("local_root")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("S3AR_LOCAL_ROOT").map(PathBuf::from));
+    let sse = build_server_side_encryption(matches)?;
+    match (local_root, sse) {
+        (Some(_), Some(_)) => Err(Error::invalid_input(
+            "--sse, --sse-kms-key-id and --sse-c-key-file can't be used with the local root",
+        )),
+        (Some(root), None) => Ok(Arc::new(store::LocalStore::new(root))),
+        (None, sse) => {
+            let mut s3_store = store::S3Store::new(rusoto_s3::S3Client::new(aws_region));
+            if let Some(sse) = sse {
+                s3_store = s3_store.with_server_side_encryption(sse);
+            }
+            Ok(Arc::new(s3_store))
+        }
+    }
+}
+
+fn build_server_side_encryption(
+    matches: &ArgMatches,
+) -> Result<Option<ServerSideEncryption>, Error> {
+    if let Some(key_file) = matches.value_of_os("sse_c_key_file") {
+        let sse = ServerSideEncryption::customer_key_from_file(key_file)
+            .map_err(|e| e.context("failed to read SSE-C key"))?;
+        return Ok(Some(sse));
+    }
+    let kms_key_id = matches.value_of("sse_kms_key_id").map(String::from);
+    let sse = match (matches.value_of("sse"), kms_key_id) {
+        (Some("AES256"), Some(_)) => {
+            return Err(Error::invalid_input("--sse-kms-key-id needs --sse aws:kms"))
+        }
+        (Some("AES256"), None) => Some(ServerSideEncryption::S3),
+        (Some(_), kms_key_id) | (None, kms_key_id @ Some(_)) => {
+            Some(ServerSideEncryption::Kms(kms_key_id))
+        }
+        (None, None) => None,
+    };
+    Ok(sse)
+}
+
+fn build_key_provider(matches: &ArgMatches) -> Result<Option<Arc<dyn KeyProvider>>, Error> {
+    let provider = if let Some(key_file) = matches.value_of_os("key_file") {
+        MasterKeyProvider::from_file(key_file)
+    } else if let Some(key_env) = matches.value_of("key_env") {
+        MasterKeyProvider::from_env(key_env)
+    } else {
+        return Ok(None);
+    };
+    let provider = provider.map_err(|e| e.context("failed to read master key"))?;
+    Ok(Some(Arc::new(provider)))
+}
+
+fn build_archive_create(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+) -> Result<create::ArchiveCreate, Error> {
+    let defaults = create::ArchiveCreate::default();
     let directory = matches.value_of_os("directory").map(Into::into);
 
     let files = sub_matches
@@ -157,23 +803,54 @@ fn build_archive_create(matches: &ArgMatches, sub_matches: &ArgMatches) -> creat
     let file_concurrency = sub_matches
         .value_of("file_concurrency")
         .map(FromStr::from_str)
-        .unwrap_or(Ok(8))
-        .expect("failed to parse file concurrency");
+        .unwrap_or(Ok(defaults.file_concurrency))
+        .map_err(|e| parse_error("file concurrency", e))?;
     let part_concurrency = sub_matches
         .value_of("part_concurrency")
         .map(FromStr::from_str)
-        .unwrap_or(Ok(8))
-        .expect("failed to parse part concurrency");
+        .unwrap_or(Ok(defaults.part_concurrency))
+        .map_err(|e| parse_error("part concurrency", e))?;
     let part_queue_size = sub_matches
         .value_of("part_queue_size")
         .map(FromStr::from_str)
-        .unwrap_or(Ok(8))
-        .expect("failed to parse part queue size");
+        .unwrap_or(Ok(defaults.part_queue_size))
+        .map_err(|e| parse_error("part queue size", e))?;
     let part_size = sub_matches
         .value_of("part_size")
         .map(FromStr::from_str)
-        .unwrap_or(Ok(16usize * 1024 * 1024))
-        .expect("failed to parse part size");
+        .unwrap_or(Ok(defaults.part_size))
+        .map_err(|e| parse_error("part size", e))?;
+    let put_threshold = sub_matches
+        .value_of("put_threshold")
+        .map(FromStr::from_str)
+        .unwrap_or(Ok(defaults.put_threshold))
+        .map_err(|e| parse_error("put threshold", e))?;
+    let pack_threshold = sub_matches
+        .value_of("pack_threshold")
+        .map(FromStr::from_str)
+        .unwrap_or(Ok(defaults.pack_threshold))
+        .map_err(|e| parse_error("pack threshold", e))?;
+    let pack_size = sub_matches
+        .value_of("pack_size")
+        .map(FromStr::from_str)
+        .unwrap_or(Ok(defaults.pack_size))
+        .map_err(|e| parse_error("pack size", e))?;
+    let compressor = sub_matches
+        .value_of("compress")
+        .map(FromStr::from_str)
+        .transpose()
+        .map_err(|e| parse_error("compression", e))?;
+    let storage_class = sub_matches.value_of("storage_class").map(String::from);
+    let tags = sub_matches
+        .values_of("tag")
+        .map(|tags| tags.map(parse_key_value).collect())
+        .unwrap_or(Ok(Vec::new()))
+        .map_err(|e| parse_error("tag", e))?;
+    let metadata = sub_matches
+        .values_of("metadata")
+        .map(|metadata| metadata.map(parse_key_value).collect())
+        .unwrap_or(Ok(Vec::new()))
+        .map_err(|e| parse_error("metadata", e))?;
 
     let s3_bucket = sub_matches
         .value_of("TARGET_BUCKET")
@@ -184,32 +861,75 @@ fn build_archive_create(matches: &ArgMatches, sub_matches: &ArgMatches) -> creat
         .expect("no s3 prefix")
         .to_string();
 
-    create::ArchiveCreate {
+    let resume = sub_matches.is_present("resume");
+    let follow_symlinks = sub_matches.is_present("follow_symlinks");
+
+    let excludes = sub_matches
+        .values_of("exclude")
+        .map(|excludes| excludes.map(Into::into).collect())
+        .unwrap_or_default();
+    let exclude_from = sub_matches
+        .values_of_os("exclude_from")
+        .map(|files| files.map(Into::into).collect())
+        .unwrap_or_default();
+    let includes = sub_matches
+        .values_of("include")
+        .map(|includes| includes.map(Into::into).collect())
+        .unwrap_or_default();
+
+    Ok(create::ArchiveCreate {
         file_concurrency,
         part_concurrency,
         part_queue_size,
         part_size,
+        put_threshold,
+        pack_threshold,
+        pack_size,
         s3_bucket,
         s3_prefix,
         directory,
         files,
-    }
+        resume,
+        follow_symlinks,
+        excludes,
+        exclude_from,
+        includes,
+        compressor,
+        storage_class,
+        tags,
+        metadata,
+    })
 }
 
+fn parse_error<E: fmt::Display>(name: &str, e: E) -> Error {
+    Error::invalid_input(format!("failed to parse {}: {}", name, e))
+}
 
-fn build_archive_extract(matches: &ArgMatches, sub_matches: &ArgMatches) -> extract::ArchiveExtract {
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let mut split = s.splitn(2, '=');
+    match (split.next(), split.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("{} isn't KEY=VALUE", s)),
+    }
+}
+
+fn build_archive_extract(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+) -> Result<extract::ArchiveExtract, Error> {
+    let defaults = extract::ArchiveExtract::default();
     let directory = matches.value_of_os("directory").map(Into::into);
 
     let file_concurrency = sub_matches
         .value_of("file_concurrency")
         .map(FromStr::from_str)
-        .unwrap_or(Ok(8))
-        .expect("failed to parse file concurrency");
+        .unwrap_or(Ok(defaults.file_concurrency))
+        .map_err(|e| parse_error("file concurrency", e))?;
     let part_concurrency = sub_matches
         .value_of("part_concurrency")
         .map(FromStr::from_str)
-        .unwrap_or(Ok(8))
-        .expect("failed to parse part concurrency");
+        .unwrap_or(Ok(defaults.part_concurrency))
+        .map_err(|e| parse_error("part concurrency", e))?;
 
     let s3_bucket = sub_matches
         .value_of("SOURCE_BUCKET")
@@ -220,11 +940,167 @@ fn build_archive_extract(matches: &ArgMatches, sub_matches: &ArgMatches) -> extr
         .expect("no s3 prefix")
         .to_string();
 
-    extract::ArchiveExtract {
+    let journal = sub_matches
+        .value_of_os("journal")
+        .map(Into::into)
+        .unwrap_or(defaults.journal);
+    let resume = sub_matches.is_present("resume");
+    let same_owner = sub_matches.is_present("same_owner");
+    let range_size = sub_matches
+        .value_of("range_size")
+        .map(FromStr::from_str)
+        .unwrap_or(Ok(defaults.range_size))
+        .map_err(|e| parse_error("range size", e))?;
+
+    let paths = sub_matches
+        .values_of("PATH")
+        .map(|paths| paths.map(Into::into).collect())
+        .unwrap_or_default();
+    let includes = sub_matches
+        .values_of("include")
+        .map(|includes| includes.map(Into::into).collect())
+        .unwrap_or_default();
+    let excludes = sub_matches
+        .values_of("exclude")
+        .map(|excludes| excludes.map(Into::into).collect())
+        .unwrap_or_default();
+    let wait_for_restore = if sub_matches.is_present("wait_for_restore") {
+        let interval = sub_matches
+            .value_of("restore_poll_interval")
+            .map(humantime::parse_duration)
+            .unwrap_or(Ok(Duration::from_secs(5 * 60)))
+            .map_err(|e| parse_error("restore poll interval", e))?;
+        Some(interval)
+    } else {
+        None
+    };
+
+    Ok(extract::ArchiveExtract {
         file_concurrency,
         part_concurrency,
         s3_bucket,
         s3_prefix,
         directory,
+        journal,
+        resume,
+        same_owner,
+        range_size,
+        paths,
+        includes,
+        excludes,
+        wait_for_restore,
+    })
+}
+
+fn build_archive_cleanup(sub_matches: &ArgMatches) -> Result<cleanup::ArchiveCleanup, Error> {
+    let defaults = cleanup::ArchiveCleanup::default();
+    let older_than = sub_matches
+        .value_of("older_than")
+        .map(humantime::parse_duration)
+        .unwrap_or(Ok(defaults.older_than))
+        .map_err(|e| parse_error("older than", e))?;
+    let dry_run = sub_matches.is_present("dry_run");
+
+    let s3_bucket = sub_matches
+        .value_of("BUCKET")
+        .expect("no s3 bucket")
+        .to_string();
+    let s3_prefix = sub_matches
+        .value_of("PREFIX")
+        .expect("no s3 prefix")
+        .to_string();
+
+    Ok(cleanup::ArchiveCleanup {
+        s3_bucket,
+        s3_prefix,
+        older_than,
+        dry_run,
+    })
+}
+
+fn build_archive_verify(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+) -> Result<verify::ArchiveVerify, Error> {
+    let directory = matches.value_of_os("directory").map(Into::into);
+
+    let file_concurrency = sub_matches
+        .value_of("file_concurrency")
+        .map(FromStr::from_str)
+        .unwrap_or(Ok(8))
+        .map_err(|e| parse_error("file concurrency", e))?;
+
+    let s3_bucket = sub_matches
+        .value_of("BUCKET")
+        .expect("no s3 bucket")
+        .to_string();
+    let s3_prefix = sub_matches
+        .value_of("PREFIX")
+        .expect("no s3 prefix")
+        .to_string();
+
+    Ok(verify::ArchiveVerify {
+        file_concurrency,
+        directory,
+        s3_bucket,
+        s3_prefix,
+    })
+}
+
+fn build_archive_restore(sub_matches: &ArgMatches) -> Result<restore::ArchiveRestore, Error> {
+    let defaults = restore::ArchiveRestore::default();
+    let file_concurrency = sub_matches
+        .value_of("file_concurrency")
+        .map(FromStr::from_str)
+        .unwrap_or(Ok(defaults.file_concurrency))
+        .map_err(|e| parse_error("file concurrency", e))?;
+    let tier = sub_matches
+        .value_of("tier")
+        .map(FromStr::from_str)
+        .unwrap_or(Ok(defaults.tier))
+        .map_err(|e| parse_error("tier", e))?;
+    let days = sub_matches
+        .value_of("days")
+        .map(FromStr::from_str)
+        .unwrap_or(Ok(defaults.days))
+        .map_err(|e| parse_error("days", e))?;
+
+    let s3_bucket = sub_matches
+        .value_of("BUCKET")
+        .expect("no s3 bucket")
+        .to_string();
+    let s3_prefix = sub_matches
+        .value_of("PREFIX")
+        .expect("no s3 prefix")
+        .to_string();
+
+    Ok(restore::ArchiveRestore {
+        file_concurrency,
+        s3_bucket,
+        s3_prefix,
+        tier,
+        days,
+    })
+}
+
+fn build_archive_list(sub_matches: &ArgMatches) -> list::ArchiveList {
+    let patterns = sub_matches
+        .values_of("PATTERN")
+        .map(|patterns| patterns.map(Into::into).collect())
+        .unwrap_or_default();
+
+    let s3_bucket = sub_matches
+        .value_of("BUCKET")
+        .expect("no s3 bucket")
+        .to_string();
+    let s3_prefix = sub_matches
+        .value_of("PREFIX")
+        .expect("no s3 prefix")
+        .to_string();
+
+    list::ArchiveList {
+        s3_bucket,
+        s3_prefix,
+        patterns,
     }
 }
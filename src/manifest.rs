@@ -0,0 +1,485 @@
+use std::cmp;
+use std::io;
+use std::time::SystemTime;
+
+use futures::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::encryption::{ArchiveEncryption, DataKey, KeyProvider};
+use super::error::{Context, Error};
+use super::file_entry::{FileEntry, Kind};
+use super::key_resolver;
+use super::store::{ObjectRange, ObjectStore};
+
+pub const FORMAT: &str = "s3ar-manifest";
+
+/// The latest version of the manifest which this s3ar reads and writes
+///
+/// - 2: the header and the file entries in JSON
+/// - 3: the entries of directories, symlinks and hard links
+/// - 4: the small files packed into pack objects
+/// - 5: the files compressed in the objects
+/// - 6: the archive encrypted by the data key wrapped in the header
+///
+/// The manifest is written in the lowest version which has the features used by the archive
+/// so that older s3ar can read it.
+pub const VERSION: u32 = 6;
+
+const BASE_VERSION: u32 = 3;
+
+const PACK_VERSION: u32 = 4;
+
+const COMPRESSION_VERSION: u32 = 5;
+
+const ENCRYPTION_VERSION: u32 = 6;
+
+const MANIFEST_AAD: &str = "manifest";
+
+/// The version of the bare `size\tpath` manifest which has no header
+pub const LEGACY_VERSION: u32 = 1;
+
+/// The first line of the manifest
+///
+/// The rest of the manifest consists of the file entries, one JSON object per line,
+/// each of which is sealed by the data key with its index if the archive is encrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub format: String,
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3ar_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_count: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<ArchiveEncryption>,
+    /// The number of the sealed entries, which tells the truncated manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry_count: Option<Sealed>,
+}
+
+impl Header {
+    fn legacy() -> Self {
+        Header {
+            format: FORMAT.to_string(),
+            version: LEGACY_VERSION,
+            s3ar_version: None,
+            created_at: None,
+            part_size: None,
+            file_count: None,
+            total_bytes: None,
+            encryption: None,
+            entry_count: None,
+        }
+    }
+}
+
+/// The JSON sealed by the data key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sealed {
+    pub nonce: String,
+    pub sealed: String,
+}
+
+impl Sealed {
+    fn seal(data_key: &DataKey, json: Vec<u8>, aad: &str) -> Result<Self, Error> {
+        let (sealed, nonce) = data_key.seal(json, aad.as_bytes())?;
+        Ok(Sealed {
+            nonce,
+            sealed: base64::encode(&sealed),
+        })
+    }
+
+    fn open(&self, data_key: &DataKey, aad: &str) -> Result<Vec<u8>, Error> {
+        let sealed = base64::decode(&self.sealed).map_err(|_| "invalid sealed data in manifest")?;
+        data_key.open(&self.nonce, &sealed, aad.as_bytes())
+    }
+}
+
+pub struct Writer {
+    version: u32,
+    part_size: usize,
+    entry_count: usize,
+    file_count: usize,
+    total_bytes: u64,
+    encryption: Option<(DataKey, ArchiveEncryption)>,
+    entries: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(part_size: usize) -> Self {
+        Writer {
+            version: BASE_VERSION,
+            part_size,
+            entry_count: 0,
+            file_count: 0,
+            total_bytes: 0,
+            encryption: None,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Seals the entries by the data key, which is wrapped in the header
+    pub(crate) fn encrypted(self, data_key: DataKey, encryption: ArchiveEncryption) -> Self {
+        Writer {
+            version: cmp::max(self.version, ENCRYPTION_VERSION),
+            encryption: Some((data_key, encryption)),
+            ..self
+        }
+    }
+
+    pub fn add(&mut self, entry: &FileEntry) -> Result<(), Error> {
+        match &self.encryption {
+            Some((data_key, _)) => {
+                let aad = entry_aad(self.entry_count);
+                let sealed = Sealed::seal(data_key, serde_json::to_vec(entry)?, &aad)?;
+                serde_json::to_writer(&mut self.entries, &sealed)?;
+            }
+            None => serde_json::to_writer(&mut self.entries, entry)?,
+        }
+        self.entries.push(b'\n');
+        self.entry_count += 1;
+        if entry.pack().is_some() {
+            self.version = cmp::max(self.version, PACK_VERSION);
+        }
+        if entry.compression().is_some() {
+            self.version = cmp::max(self.version, COMPRESSION_VERSION);
+        }
+        if entry.kind() == Kind::File {
+            self.file_count += 1;
+            self.total_bytes += entry.size() as u64;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        let entry_count = match &self.encryption {
+            Some((data_key, _)) => {
+                let json = serde_json::to_vec(&self.entry_count)?;
+                Some(Sealed::seal(data_key, json, &count_aad())?)
+            }
+            None => None,
+        };
+        let header = Header {
+            format: FORMAT.to_string(),
+            version: self.version,
+            s3ar_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            created_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+            part_size: Some(self.part_size),
+            file_count: Some(self.file_count),
+            total_bytes: Some(self.total_bytes),
+            encryption: self.encryption.map(|(_, encryption)| encryption),
+            entry_count,
+        };
+        let mut manifest = serde_json::to_vec(&header)?;
+        manifest.push(b'\n');
+        manifest.extend_from_slice(&self.entries);
+        Ok(manifest)
+    }
+}
+
+pub type Entries = stream::BoxStream<'static, Result<FileEntry, Error>>;
+
+pub struct Reader {
+    header: Header,
+    entries: Entries,
+    data_key: Option<DataKey>,
+}
+
+impl Reader {
+    /// The key provider is needed to read the encrypted archive.
+    pub async fn get(
+        store: &dyn ObjectStore,
+        s3_bucket: &str,
+        s3_prefix: &str,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<Self, Error> {
+        let manifest_key = key_resolver::manifest_key(s3_prefix);
+        let result = async {
+            let output = store
+                .get_object(s3_bucket, &manifest_key, ObjectRange::Whole)
+                .await?;
+            let lines = output.body.into_async_read().lines();
+            Self::read(lines, key_provider).await
+        };
+        result
+            .await
+            .context(|| format!("failed to read manifest {}", manifest_key))
+    }
+
+    /// The legacy manifest is accepted as the version 1 and its header only has the version.
+    /// The empty manifest is a legacy one of the empty archive.
+    pub async fn read<S>(lines: S, key_provider: Option<&dyn KeyProvider>) -> Result<Self, Error>
+    where
+        S: Stream<Item = io::Result<String>> + Send + 'static,
+    {
+        let (first, lines) = lines.boxed().into_future().await;
+        let first = match first.transpose()? {
+            Some(first) if first.starts_with('{') => first,
+            first => {
+                let entries = stream::iter(first.map(Ok))
+                    .chain(lines)
+                    .map_err(Error::from)
+                    .and_then(|line| future::ready(parse_legacy_entry(&line)))
+                    .boxed();
+                let header = Header::legacy();
+                return Ok(Reader {
+                    header,
+                    entries,
+                    data_key: None,
+                });
+            }
+        };
+        let header: Header = serde_json::from_str(&first)?;
+        if header.format != FORMAT {
+            return Err(format!("unknown manifest format: {}", header.format).into());
+        }
+        if header.version > VERSION {
+            return Err(format!(
+                "manifest version {} is not supported by this s3ar (up to version {}), upgrade s3ar",
+                header.version, VERSION
+            )
+            .into());
+        }
+        let data_key = match (&header.encryption, key_provider) {
+            (Some(encryption), Some(key_provider)) => {
+                Some(DataKey::unwrap(key_provider, encryption).await?)
+            }
+            (Some(_), None) => {
+                return Err(Error::invalid_input(
+                    "archive is encrypted, the key is needed",
+                ))
+            }
+            (None, _) => None,
+        };
+        let entry_count = match (&data_key, &header.entry_count) {
+            (Some(data_key), Some(sealed)) => {
+                let json = sealed.open(data_key, &count_aad())?;
+                Some(serde_json::from_slice::<usize>(&json)?)
+            }
+            (Some(_), None) => {
+                return Err(Error::integrity("no entry count in encrypted manifest"));
+            }
+            (None, _) => None,
+        };
+        let entry_key = data_key.clone();
+        // the end of the lines is checked against the count
+        let entries = lines
+            .map(Some)
+            .chain(stream::once(future::ready(None)))
+            .enumerate()
+            .filter_map(move |(index, line)| {
+                let result = match (line, entry_count) {
+                    (Some(line), _) => line
+                        .map_err(Error::from)
+                        .and_then(|line| parse_entry(&line, index, entry_key.as_ref())),
+                    (None, Some(count)) if count != index => {
+                        let message = format!("manifest has {} of {} entries", index, count);
+                        Err(Error::integrity(message))
+                    }
+                    (None, _) => return future::ready(None),
+                };
+                future::ready(Some(result))
+            })
+            .boxed();
+        Ok(Reader {
+            header,
+            entries,
+            data_key,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub(crate) fn data_key(&self) -> Option<&DataKey> {
+        self.data_key.as_ref()
+    }
+
+    pub fn into_parts(self) -> (Header, Entries) {
+        (self.header, self.entries)
+    }
+}
+
+/// The entry is bound to its index so that the entries can't be reordered or dropped.
+fn parse_entry(line: &str, index: usize, data_key: Option<&DataKey>) -> Result<FileEntry, Error> {
+    let data_key = match data_key {
+        Some(data_key) => data_key,
+        None => return Ok(serde_json::from_str(line)?),
+    };
+    let sealed: Sealed = serde_json::from_str(line)?;
+    let json = sealed.open(data_key, &entry_aad(index))?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+fn entry_aad(index: usize) -> String {
+    format!("{}\t{}", MANIFEST_AAD, index)
+}
+
+fn count_aad() -> String {
+    format!("{}\tcount", MANIFEST_AAD)
+}
+
+fn parse_legacy_entry(line: &str) -> Result<FileEntry, Error> {
+    let mut cols = line.splitn(2, '\t');
+    let size = cols
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .map_err(|e| format!("invalid size in manifest line {:?}: {}", line, e))?;
+    let path = cols.next().ok_or("no path in manifest")?;
+    Ok(FileEntry::new(path.to_string(), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::*;
+    use crate::encryption::MasterKeyProvider;
+    use crate::error::ErrorKind;
+
+    async fn read(
+        manifest: &[u8],
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<Reader, Error> {
+        let lines = Cursor::new(manifest.to_vec()).lines();
+        Reader::read(lines, key_provider).await
+    }
+
+    async fn read_entries(reader: Reader) -> Vec<(String, usize)> {
+        let (_, entries) = reader.into_parts();
+        let entries: Vec<FileEntry> = entries.try_collect().await.unwrap();
+        entries
+            .iter()
+            .map(|entry| (entry.path().to_string(), entry.size()))
+            .collect()
+    }
+
+    fn read_err(result: Result<Reader, Error>) -> Error {
+        match result {
+            Ok(_) => panic!("manifest was read"),
+            Err(e) => e,
+        }
+    }
+
+    fn write(mut writer: Writer, entries: &[(&str, usize)]) -> Vec<u8> {
+        for (path, size) in entries {
+            writer
+                .add(&FileEntry::new(path.to_string(), *size))
+                .unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn counts_only_files() {
+        let mut writer = Writer::new(1024);
+        let mut entry = |entry| writer.add(&entry).unwrap();
+        entry(FileEntry::new("a".to_string(), 3));
+        entry(FileEntry::new("b".to_string(), 0).symlink_to("a".to_string()));
+        entry(FileEntry::new("c".to_string(), 0).hard_link_to("a".to_string()));
+        let manifest = writer.finish().unwrap();
+        let first = manifest.split(|&b| b == b'\n').next().unwrap();
+        let header: Header = serde_json::from_slice(first).unwrap();
+        assert_eq!(header.file_count, Some(1));
+        assert_eq!(header.total_bytes, Some(3));
+    }
+
+    #[test]
+    fn writes_header() {
+        let manifest = write(Writer::new(1024), &[("a", 3), ("b/c", 5)]);
+        let first = manifest.split(|&b| b == b'\n').next().unwrap();
+        let header: Header = serde_json::from_slice(first).unwrap();
+        assert_eq!(header.format, FORMAT);
+        assert_eq!(header.version, BASE_VERSION);
+        assert_eq!(header.part_size, Some(1024));
+        assert_eq!(header.file_count, Some(2));
+        assert_eq!(header.total_bytes, Some(8));
+        assert!(header.encryption.is_none());
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let manifest = write(Writer::new(1024), &[("a", 3), ("b/c", 5)]);
+        let reader = read(&manifest, None).await.unwrap();
+        assert_eq!(reader.header().version, BASE_VERSION);
+        assert!(reader.data_key().is_none());
+        let entries = read_entries(reader).await;
+        assert_eq!(entries, vec![("a".to_string(), 3), ("b/c".to_string(), 5)]);
+    }
+
+    #[tokio::test]
+    async fn round_trips_encrypted() {
+        let provider = MasterKeyProvider::new(&[7; 32]).unwrap();
+        let (data_key, encryption) = DataKey::generate(&provider).await.unwrap();
+        let writer = Writer::new(1024).encrypted(data_key, encryption);
+        let manifest = write(writer, &[("a", 3)]);
+        assert!(!String::from_utf8_lossy(&manifest).contains("\"a\""));
+
+        let reader = read(&manifest, Some(&provider)).await.unwrap();
+        assert_eq!(reader.header().version, ENCRYPTION_VERSION);
+        assert!(reader.data_key().is_some());
+        assert_eq!(read_entries(reader).await, vec![("a".to_string(), 3)]);
+
+        let e = read_err(read(&manifest, None).await);
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn rejects_reordered_or_truncated_entries() {
+        let provider = MasterKeyProvider::new(&[7; 32]).unwrap();
+        let (data_key, encryption) = DataKey::generate(&provider).await.unwrap();
+        let writer = Writer::new(1024).encrypted(data_key, encryption);
+        let manifest = write(writer, &[("a", 3), ("b", 5), ("c", 7)]);
+        let lines: Vec<_> = manifest.split(|&b| b == b'\n').collect();
+        let tampered = |indices: &[usize]| {
+            let mut tampered: Vec<_> = indices.iter().map(|&i| lines[i]).collect();
+            tampered.insert(0, lines[0]);
+            tampered.join(&b'\n')
+        };
+        for manifest in &[tampered(&[2, 1, 3]), tampered(&[1, 3]), tampered(&[1, 2])] {
+            let (_, entries) = read(manifest, Some(&provider)).await.unwrap().into_parts();
+            let result: Result<Vec<_>, _> = entries.try_collect().await;
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::Integrity);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_legacy() {
+        let reader = read(b"3\ta\n5\tb c\n", None).await.unwrap();
+        assert_eq!(reader.header().version, LEGACY_VERSION);
+        let entries = read_entries(reader).await;
+        assert_eq!(entries, vec![("a".to_string(), 3), ("b c".to_string(), 5)]);
+    }
+
+    #[tokio::test]
+    async fn reads_empty_as_legacy() {
+        let reader = read(b"", None).await.unwrap();
+        assert_eq!(reader.header().version, LEGACY_VERSION);
+        assert!(read_entries(reader).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_future_version() {
+        let manifest = format!(
+            "{{\"format\":\"{}\",\"version\":{}}}\n{{\"path\":\"a\",\"size\":3}}\n",
+            FORMAT,
+            VERSION + 1
+        );
+        let e = read_err(read(manifest.as_bytes(), None).await);
+        assert!(e.to_string().contains("upgrade s3ar"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_format() {
+        let e = read_err(read(b"{\"format\":\"tar\",\"version\":1}\n", None).await);
+        assert!(e.to_string().contains("unknown manifest format"), "{}", e);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::progress::{Progress, ProgressCallback};
+
+const BAR_INTERVAL: Duration = Duration::from_millis(200);
+
+const LINE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The throughput is averaged over this window so that a stuck transfer shows zero soon
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+const BAR_WIDTH: usize = 30;
+
+/// Shows the progress of the transfer on stderr until dropped
+///
+/// It's a live bar on a terminal, otherwise plain lines at intervals.
+pub struct ProgressMeter {
+    tally: Arc<Mutex<Tally>>,
+    stop: mpsc::Sender<()>,
+    drawer: Option<JoinHandle<()>>,
+}
+
+impl ProgressMeter {
+    pub fn start() -> Self {
+        let live = nix::unistd::isatty(io::stderr().as_raw_fd()).unwrap_or(false);
+        let interval = if live { BAR_INTERVAL } else { LINE_INTERVAL };
+        let tally = Arc::new(Mutex::new(Tally::default()));
+        let (stop, stopped) = mpsc::channel();
+        let drawer = {
+            let tally = tally.clone();
+            thread::spawn(move || loop {
+                let last = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                let line = tally.lock().unwrap().line(Instant::now(), live);
+                match (live, last) {
+                    (true, false) => eprint!("\r{}\x1b[K", line),
+                    (true, true) => eprintln!("\r{}\x1b[K", line),
+                    (false, _) => eprintln!("{}", line),
+                }
+                if last {
+                    break;
+                }
+            })
+        };
+        ProgressMeter {
+            tally,
+            stop,
+            drawer: Some(drawer),
+        }
+    }
+
+    pub fn callback(&self) -> ProgressCallback {
+        let tally = self.tally.clone();
+        Arc::new(move |progress: &Progress| tally.lock().unwrap().update(progress))
+    }
+}
+
+impl Drop for ProgressMeter {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(drawer) = self.drawer.take() {
+            let _ = drawer.join();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tally {
+    total: Option<(usize, u64)>,
+    files_done: usize,
+    bytes_done: u64,
+    ongoing: HashMap<String, (u64, u64)>,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Tally {
+    fn update(&mut self, progress: &Progress) {
+        match progress {
+            Progress::Total { files, bytes } => self.total = Some((*files, *bytes)),
+            Progress::FileStarted { path, size } => {
+                self.ongoing.insert(path.clone(), (*size as u64, 0));
+            }
+            Progress::PartCompleted { path, size, .. } => {
+                self.bytes_done += *size as u64;
+                if let Some((_, done)) = self.ongoing.get_mut(path) {
+                    *done += *size as u64;
+                }
+            }
+            Progress::FileCompleted { path } => {
+                self.files_done += 1;
+                // the parts transferred by the interrupted run aren't reported
+                if let Some((size, done)) = self.ongoing.remove(path) {
+                    self.bytes_done += size.saturating_sub(done);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn throughput(&mut self, now: Instant) -> f64 {
+        self.samples.push_back((now, self.bytes_done));
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= THROUGHPUT_WINDOW {
+            self.samples.pop_front();
+        }
+        let (since, bytes) = self.samples[0];
+        let elapsed = now.duration_since(since).as_secs_f64();
+        if elapsed > 0.0 {
+            (self.bytes_done - bytes) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn line(&mut self, now: Instant, bar: bool) -> String {
+        let throughput = self.throughput(now);
+        let (files_total, bytes_total) = match self.total {
+            Some(total) => total,
+            None => {
+                return format!(
+                    "{} files, {}, {}/s",
+                    self.files_done,
+                    format_bytes(self.bytes_done as f64),
+                    format_bytes(throughput)
+                );
+            }
+        };
+        let eta = match bytes_total.saturating_sub(self.bytes_done) {
+            0 => "0s".to_string(),
+            _ if throughput < 1.0 => "-".to_string(),
+            rest => {
+                let secs = (rest as f64 / throughput).ceil() as u64;
+                humantime::format_duration(Duration::from_secs(secs)).to_string()
+            }
+        };
+        let line = format!(
+            "{}/{} files, {}/{}, {}/s, ETA {}",
+            self.files_done,
+            files_total,
+            format_bytes(self.bytes_done as f64),
+            format_bytes(bytes_total as f64),
+            format_bytes(throughput),
+            eta
+        );
+        if !bar {
+            return line;
+        }
+        let ratio = match bytes_total {
+            0 if files_total == 0 => 1.0,
+            0 => self.files_done as f64 / files_total as f64,
+            _ => self.bytes_done as f64 / bytes_total as f64,
+        };
+        let filled = ((ratio * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+        format!(
+            "[{}{}] {:3.0}% {}",
+            "#".repeat(filled),
+            ".".repeat(BAR_WIDTH - filled),
+            ratio.min(1.0) * 100.0,
+            line
+        )
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
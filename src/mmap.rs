@@ -5,6 +5,7 @@ use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
 use nix::sys::mman;
+use nix::unistd::{sysconf, SysconfVar};
 
 #[derive(Debug)]
 pub struct Handle {
@@ -37,7 +38,7 @@ impl Handle {
 
 impl Drop for Handle {
     fn drop(&mut self) {
-        if self.ptr == ptr::null_mut() {
+        if self.ptr.is_null() {
             return;
         }
         unsafe {
@@ -72,7 +73,7 @@ impl Chunker {
             len,
         };
         self.offset += len;
-        return chunk;
+        chunk
     }
 }
 
@@ -83,6 +84,21 @@ pub struct Chunk {
     len: usize,
 }
 
+impl Chunk {
+    pub fn sync(&self) -> nix::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        // msync needs the address aligned to the page
+        let page_size = sysconf(SysconfVar::PAGE_SIZE)?.unwrap_or(4096) as usize;
+        let start = self.offset - self.offset % page_size;
+        unsafe {
+            let ptr = (self.handle.ptr as *mut u8).add(start) as *mut c_void;
+            mman::msync(ptr, self.offset + self.len - start, mman::MsFlags::MS_SYNC)
+        }
+    }
+}
+
 impl Deref for Chunk {
     type Target = [u8];
     fn deref(&self) -> &Self::Target {
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    Total { files: usize, bytes: u64 },
+    FileStarted { path: String, size: usize },
+    PartCompleted {
+        path: String,
+        part_number: i64,
+        size: usize,
+        elapsed: Duration,
+    },
+    FileCompleted { path: String },
+    Skipped { path: String, reason: String },
+    Restoring { pending: usize },
+    Retrying {
+        retry: u32,
+        wait: Duration,
+        cause: String,
+    },
+}
+
+/// It's called by the tasks transferring files, so it should return quickly.
+pub type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct Reporter(Option<ProgressCallback>);
+
+impl Reporter {
+    pub fn new(callback: ProgressCallback) -> Self {
+        Reporter(Some(callback))
+    }
+
+    pub fn report(&self, progress: Progress) {
+        if let Some(callback) = &self.0 {
+            callback(&progress);
+        }
+    }
+}
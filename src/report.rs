@@ -0,0 +1,200 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::progress::{Progress, ProgressCallback};
+use super::Error;
+
+/// Records the events of the command, which are written to stderr in JSON lines if enabled,
+/// and sums them up into the report
+pub struct EventLog {
+    command: String,
+    json: bool,
+    started: Instant,
+    tally: Arc<Mutex<Tally>>,
+}
+
+#[derive(Debug, Default)]
+struct Tally {
+    files: usize,
+    bytes: u64,
+    retries: usize,
+    ongoing: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub command: String,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    pub files: usize,
+    pub bytes: u64,
+    pub failed: usize,
+    pub elapsed_secs: f64,
+    pub throughput: f64,
+    pub retries: usize,
+    pub failed_files: Vec<String>,
+}
+
+impl Report {
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut content = serde_json::to_vec_pretty(self)?;
+        content.push(b'\n');
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+impl EventLog {
+    pub fn new(command: &str, json: bool) -> Self {
+        EventLog {
+            command: command.to_string(),
+            json,
+            started: Instant::now(),
+            tally: Default::default(),
+        }
+    }
+
+    pub fn start(&self, bucket: &str, prefix: &str) {
+        if self.json {
+            emit(json!({
+                "event": "archive_started",
+                "command": self.command,
+                "bucket": bucket,
+                "prefix": prefix,
+            }));
+        }
+    }
+
+    pub fn callback(&self) -> ProgressCallback {
+        let tally = self.tally.clone();
+        let json = self.json;
+        let part_event = match self.command.as_str() {
+            "upload" => "part_uploaded",
+            _ => "part_downloaded",
+        };
+        Arc::new(move |progress: &Progress| {
+            tally.lock().unwrap().update(progress);
+            if json {
+                emit(event(progress, part_event));
+            }
+        })
+    }
+
+    pub fn finish(&self, error: Option<&Error>) -> Report {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let tally = self.tally.lock().unwrap();
+        let throughput = if elapsed > 0.0 {
+            tally.bytes as f64 / elapsed
+        } else {
+            0.0
+        };
+        let failed_files: Vec<_> = match error {
+            Some(_) => tally.ongoing.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+        let report = Report {
+            command: self.command.clone(),
+            succeeded: error.is_none(),
+            error: error.map(|e| e.chain().join(": ")),
+            files: tally.files,
+            bytes: tally.bytes,
+            failed: failed_files.len(),
+            elapsed_secs: elapsed,
+            throughput,
+            retries: tally.retries,
+            failed_files,
+        };
+        if self.json {
+            match &report.error {
+                Some(error) => emit(json!({
+                    "event": "error",
+                    "command": report.command,
+                    "error": error,
+                    "failed_files": report.failed_files,
+                })),
+                None => emit(json!({
+                    "event": "completed",
+                    "command": report.command,
+                    "files": report.files,
+                    "bytes": report.bytes,
+                    "elapsed_secs": report.elapsed_secs,
+                })),
+            }
+        }
+        report
+    }
+}
+
+impl Tally {
+    fn update(&mut self, progress: &Progress) {
+        match progress {
+            Progress::FileStarted { path, .. } => {
+                self.ongoing.insert(path.clone());
+            }
+            Progress::PartCompleted { size, .. } => self.bytes += *size as u64,
+            Progress::FileCompleted { path } => {
+                self.ongoing.remove(path);
+                self.files += 1;
+            }
+            Progress::Retrying { .. } => self.retries += 1,
+            _ => {}
+        }
+    }
+}
+
+fn event(progress: &Progress, part_event: &str) -> Value {
+    match progress {
+        Progress::Total { files, bytes } => json!({
+            "event": "total",
+            "files": files,
+            "bytes": bytes,
+        }),
+        Progress::FileStarted { path, size } => json!({
+            "event": "file_started",
+            "path": path,
+            "size": size,
+        }),
+        Progress::PartCompleted {
+            path,
+            part_number,
+            size,
+            elapsed,
+        } => json!({
+            "event": part_event,
+            "path": path,
+            "part_number": part_number,
+            "bytes": size,
+            "duration_secs": elapsed.as_secs_f64(),
+        }),
+        Progress::FileCompleted { path } => json!({
+            "event": "file_finished",
+            "path": path,
+        }),
+        Progress::Skipped { path, reason } => json!({
+            "event": "file_skipped",
+            "path": path,
+            "reason": reason,
+        }),
+        Progress::Restoring { pending } => json!({
+            "event": "waiting_for_restore",
+            "pending": pending,
+        }),
+        Progress::Retrying { retry, wait, cause } => json!({
+            "event": "retry",
+            "retry": retry,
+            "wait_secs": wait.as_secs_f64(),
+            "cause": cause,
+        }),
+    }
+}
+
+fn emit(mut event: Value) {
+    let time = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+    event["time"] = Value::String(time);
+    eprintln!("{}", event);
+}
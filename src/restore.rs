@@ -0,0 +1,213 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::prelude::*;
+use tokio::time::delay_for;
+
+use super::encryption::KeyProvider;
+use super::key_resolver;
+use super::manifest;
+use super::progress::{Progress, Reporter};
+use super::store::{Availability, ObjectStore, RestoreTier};
+use super::utils::with_retry;
+use super::Error;
+
+#[derive(Debug, Clone)]
+pub struct ArchiveRestore {
+    pub file_concurrency: usize,
+    pub s3_bucket: String,
+    pub s3_prefix: String,
+    pub tier: RestoreTier,
+    pub days: i64,
+}
+
+impl Default for ArchiveRestore {
+    fn default() -> Self {
+        ArchiveRestore {
+            file_concurrency: 8,
+            s3_bucket: String::new(),
+            s3_prefix: String::new(),
+            tier: RestoreTier::Standard,
+            days: 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Available,
+    Restoring,
+    Requested,
+    Missing,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Available => write!(f, "available"),
+            State::Restoring => write!(f, "restoring"),
+            State::Requested => write!(f, "requested"),
+            State::Missing => write!(f, "missing-object"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Total {
+    pub available: usize,
+    pub restoring: usize,
+    pub requested: usize,
+    pub missing: usize,
+}
+
+pub type StateCallback = Arc<dyn Fn(&str, State) + Send + Sync>;
+
+pub struct RestoreExecutor {
+    store: Arc<dyn ObjectStore>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    on_state: Option<StateCallback>,
+}
+
+impl RestoreExecutor {
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            key_provider: None,
+            on_state: None,
+        }
+    }
+
+    pub fn on_state(mut self, callback: StateCallback) -> Self {
+        self.on_state = Some(callback);
+        self
+    }
+
+    pub fn with_key_provider(mut self, key_provider: Arc<dyn KeyProvider>) -> Self {
+        self.key_provider = Some(key_provider);
+        self
+    }
+
+    /// Requests restoring the archived objects of the archive and totals the states of them,
+    /// where the missing objects are counted rather than failing the rest
+    ///
+    /// Running it again tells which objects have been restored since.
+    pub async fn execute(
+        &self,
+        ArchiveRestore {
+            file_concurrency,
+            s3_bucket,
+            s3_prefix,
+            tier,
+            days,
+        }: ArchiveRestore,
+    ) -> Result<Total, Error> {
+        if days < 1 {
+            return Err(Error::invalid_input("days must be positive"));
+        }
+        let key_provider = self.key_provider.as_deref();
+        let manifest =
+            manifest::Reader::get(self.store.as_ref(), &s3_bucket, &s3_prefix, key_provider)
+                .await?;
+        let data_key = manifest.data_key().cloned();
+        let (_, entries) = manifest.into_parts();
+        let keys = entries
+            .try_fold(BTreeSet::new(), |mut keys, entry| {
+                let key = key_resolver::object_key(&s3_prefix, &entry, data_key.as_ref());
+                keys.extend(key);
+                future::ok(keys)
+            })
+            .await?;
+        let s3_bucket = &s3_bucket;
+        let mut total = Total::default();
+        stream::iter(keys)
+            .map(|key| async move {
+                let state = self.restore(s3_bucket, &key, tier, days).await?;
+                Ok::<_, Error>((key, state))
+            })
+            .buffer_unordered(file_concurrency)
+            .try_for_each(|(key, state)| {
+                match state {
+                    State::Available => total.available += 1,
+                    State::Restoring => total.restoring += 1,
+                    State::Requested => total.requested += 1,
+                    State::Missing => total.missing += 1,
+                }
+                if let Some(callback) = &self.on_state {
+                    callback(&key, state);
+                }
+                future::ok(())
+            })
+            .await?;
+        Ok(total)
+    }
+
+    async fn restore(
+        &self,
+        bucket: &str,
+        key: &str,
+        tier: RestoreTier,
+        days: i64,
+    ) -> Result<State, Error> {
+        let output = with_retry(10, 1, 5, || self.store.head_object(bucket, key)).await?;
+        let availability = match output {
+            Some(output) => output.availability,
+            None => return Ok(State::Missing),
+        };
+        match availability {
+            Availability::Available => Ok(State::Available),
+            Availability::Restoring => Ok(State::Restoring),
+            Availability::Archived => {
+                with_retry(10, 1, 5, || {
+                    self.store.restore_object(bucket, key, tier, days)
+                })
+                .await?;
+                Ok(State::Requested)
+            }
+        }
+    }
+}
+
+/// Polls the objects at the interval until all of them are available,
+/// which fails if any of them is archived and not being restored
+pub(crate) async fn wait_until_available(
+    store: &dyn ObjectStore,
+    bucket: &str,
+    mut keys: Vec<String>,
+    concurrency: usize,
+    interval: Duration,
+    reporter: &Reporter,
+) -> Result<(), Error> {
+    loop {
+        let heads: Vec<_> = stream::iter(keys)
+            .map(|key| async move {
+                let output = with_retry(10, 1, 5, || store.head_object(bucket, &key)).await?;
+                let output = match output {
+                    Some(output) => output,
+                    None => return Err(Error::not_found(format!("{} not found", key))),
+                };
+                Ok::<_, Error>((key, output.availability))
+            })
+            .buffer_unordered(concurrency)
+            .try_collect()
+            .await?;
+        keys = Vec::new();
+        for (key, availability) in heads {
+            match availability {
+                Availability::Available => {}
+                Availability::Restoring => keys.push(key),
+                Availability::Archived => {
+                    return Err(format!("{} is archived and not being restored", key).into())
+                }
+            }
+        }
+        if keys.is_empty() {
+            return Ok(());
+        }
+        reporter.report(Progress::Restoring {
+            pending: keys.len(),
+        });
+        delay_for(interval).await;
+    }
+}
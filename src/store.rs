@@ -0,0 +1,357 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use futures::prelude::*;
+
+use super::error::Error;
+
+mod local;
+mod memory;
+mod s3;
+
+pub use local::LocalStore;
+pub use memory::MemoryStore;
+pub use s3::{S3Store, ServerSideEncryption};
+
+pub type StoreFuture<T> = future::BoxFuture<'static, Result<T, Error>>;
+
+pub type ByteStream = stream::BoxStream<'static, io::Result<Vec<u8>>>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ObjectRange {
+    Whole,
+    /// The part numbered from 1 if the object was completed by a multipart upload,
+    /// otherwise the part 1 is the whole object
+    Part(i64),
+    /// The bytes from the offset with the length, which must not be zero
+    Bytes(usize, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreTier {
+    Bulk,
+    Standard,
+    Expedited,
+}
+
+impl fmt::Display for RestoreTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestoreTier::Bulk => write!(f, "Bulk"),
+            RestoreTier::Standard => write!(f, "Standard"),
+            RestoreTier::Expedited => write!(f, "Expedited"),
+        }
+    }
+}
+
+impl FromStr for RestoreTier {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Bulk" => Ok(RestoreTier::Bulk),
+            "Standard" => Ok(RestoreTier::Standard),
+            "Expedited" => Ok(RestoreTier::Expedited),
+            _ => Err(format!("unknown restore tier: {}", s).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+    Available,
+    Archived,
+    Restoring,
+}
+
+/// The attributes given to the objects on uploading, which the stores may ignore
+#[derive(Debug, Clone, Default)]
+pub struct ObjectAttributes {
+    pub content_type: Option<String>,
+    pub storage_class: Option<String>,
+    pub tags: Vec<(String, String)>,
+    /// The user metadata without the `x-amz-meta-` prefix
+    pub metadata: Vec<(String, String)>,
+}
+
+pub struct GetObjectOutput {
+    pub body: ByteStream,
+    pub content_length: usize,
+    /// The number of the parts if the part of the object completed by a multipart upload is got
+    pub parts_count: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeadObjectOutput {
+    pub content_length: usize,
+    pub e_tag: Option<String>,
+    pub availability: Availability,
+}
+
+#[derive(Debug, Clone)]
+pub struct MultipartUploadInfo {
+    pub key: String,
+    pub upload_id: String,
+    /// The time in RFC 3339
+    pub initiated: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListMultipartUploadsOutput {
+    pub uploads: Vec<MultipartUploadInfo>,
+    pub next_markers: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PartInfo {
+    pub part_number: i64,
+    pub e_tag: String,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListPartsOutput {
+    pub parts: Vec<PartInfo>,
+    pub next_marker: Option<i64>,
+}
+
+/// The futures own their arguments so that they can be retried and spawned.
+pub trait ObjectStore: Send + Sync {
+    fn create_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        attributes: &ObjectAttributes,
+    ) -> StoreFuture<String>;
+
+    fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> StoreFuture<String>;
+
+    fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<(i64, String)>,
+    ) -> StoreFuture<()>;
+
+    fn abort_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str) -> StoreFuture<()>;
+
+    fn list_multipart_uploads(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        markers: Option<(String, String)>,
+    ) -> StoreFuture<ListMultipartUploadsOutput>;
+
+    fn list_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        marker: Option<i64>,
+    ) -> StoreFuture<ListPartsOutput>;
+
+    fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+        attributes: &ObjectAttributes,
+    ) -> StoreFuture<()>;
+
+    fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: ObjectRange,
+    ) -> StoreFuture<GetObjectOutput>;
+
+    /// Returns `None` if the object doesn't exist
+    fn head_object(&self, bucket: &str, key: &str) -> StoreFuture<Option<HeadObjectOutput>>;
+
+    /// Starts restoring the archived object to keep the copy for the days,
+    /// which succeeds if the restore of it is in progress already
+    fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        tier: RestoreTier,
+        days: i64,
+    ) -> StoreFuture<()>;
+}
+
+pub fn e_tag(body: &[u8]) -> String {
+    format!("\"{:x}\"", md5::compute(body))
+}
+
+pub fn multipart_e_tag(part_digests: &[md5::Digest]) -> String {
+    let digests: Vec<u8> = part_digests
+        .iter()
+        .flat_map(|digest| digest.0.to_vec())
+        .collect();
+    format!("\"{:x}-{}\"", md5::compute(&digests), part_digests.len())
+}
+
+fn parts_count(part_sizes: Option<&[usize]>, range: ObjectRange) -> Option<i64> {
+    match range {
+        ObjectRange::Part(_) => part_sizes.map(|sizes| sizes.len() as i64),
+        ObjectRange::Whole | ObjectRange::Bytes(..) => None,
+    }
+}
+
+fn part_range(
+    part_sizes: Option<&[usize]>,
+    len: usize,
+    range: ObjectRange,
+) -> Result<(usize, usize), Error> {
+    match (range, part_sizes) {
+        (ObjectRange::Whole, _) | (ObjectRange::Part(1), None) => Ok((0, len)),
+        (ObjectRange::Part(part_number), Some(sizes))
+            if part_number >= 1 && part_number as usize <= sizes.len() =>
+        {
+            let index = part_number as usize - 1;
+            Ok((sizes[..index].iter().sum(), sizes[index]))
+        }
+        (ObjectRange::Part(part_number), _) => {
+            Err(format!("invalid part number: {}", part_number).into())
+        }
+        (ObjectRange::Bytes(offset, range_len), _)
+            if range_len > 0 && offset + range_len <= len =>
+        {
+            Ok((offset, range_len))
+        }
+        (ObjectRange::Bytes(offset, range_len), _) => {
+            Err(format!("invalid range: {}+{} of {} bytes", offset, range_len, len).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::utils::test_dir;
+
+    async fn get(store: &dyn ObjectStore, key: &str, range: ObjectRange) -> (Vec<u8>, Option<i64>) {
+        let output = store.get_object("bucket", key, range).await.unwrap();
+        let body = output.body.try_concat().await.unwrap();
+        assert_eq!(body.len(), output.content_length);
+        (body, output.parts_count)
+    }
+
+    async fn stores_objects(store: &dyn ObjectStore) {
+        let attributes = ObjectAttributes::default();
+        let upload_id = store.create_multipart_upload("bucket", "a/b", &attributes);
+        let upload_id = upload_id.await.unwrap();
+        let mut parts = Vec::new();
+        for (part_number, body) in &[(1, b"abc".to_vec()), (2, b"de".to_vec())] {
+            let part = store.upload_part("bucket", "a/b", &upload_id, *part_number, body.clone());
+            parts.push((*part_number, part.await.unwrap()));
+        }
+        let uploads = store
+            .list_multipart_uploads("bucket", "a/", None)
+            .await
+            .unwrap();
+        assert_eq!(uploads.uploads.len(), 1);
+        assert_eq!(uploads.uploads[0].key, "a/b");
+        let listed = store
+            .list_parts("bucket", "a/b", &upload_id, Some(1))
+            .await
+            .unwrap();
+        let listed: Vec<_> = listed
+            .parts
+            .iter()
+            .map(|p| (p.part_number, p.size))
+            .collect();
+        assert_eq!(listed, vec![(2, 2)]);
+        let completed = store.complete_multipart_upload("bucket", "a/b", &upload_id, parts);
+        completed.await.unwrap();
+        let uploads = store
+            .list_multipart_uploads("bucket", "", None)
+            .await
+            .unwrap();
+        assert!(uploads.uploads.is_empty());
+
+        let head = store.head_object("bucket", "a/b").await.unwrap().unwrap();
+        assert_eq!(head.content_length, 5);
+        let digests = [md5::compute(b"abc"), md5::compute(b"de")];
+        assert_eq!(head.e_tag, Some(multipart_e_tag(&digests)));
+        let part = get(store, "a/b", ObjectRange::Part(2)).await;
+        assert_eq!(part, (b"de".to_vec(), Some(2)));
+        let range = get(store, "a/b", ObjectRange::Bytes(1, 3)).await;
+        assert_eq!(range, (b"bcd".to_vec(), None));
+
+        store
+            .put_object("bucket", "c", b"xyz".to_vec(), &attributes)
+            .await
+            .unwrap();
+        let head = store.head_object("bucket", "c").await.unwrap().unwrap();
+        assert_eq!(head.e_tag, Some(e_tag(b"xyz")));
+        let part = get(store, "c", ObjectRange::Part(1)).await;
+        assert_eq!(part, (b"xyz".to_vec(), None));
+
+        assert!(store.head_object("bucket", "d").await.unwrap().is_none());
+        let e = store
+            .get_object("bucket", "d", ObjectRange::Whole)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        let e = store
+            .abort_multipart_upload("bucket", "a/b", &upload_id)
+            .await;
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn splits_parts_and_ranges() {
+        let sizes = [3, 2];
+        assert_eq!(
+            part_range(Some(&sizes), 5, ObjectRange::Part(2)).unwrap(),
+            (3, 2)
+        );
+        assert_eq!(part_range(None, 5, ObjectRange::Part(1)).unwrap(), (0, 5));
+        assert_eq!(part_range(None, 5, ObjectRange::Whole).unwrap(), (0, 5));
+        assert_eq!(
+            part_range(None, 5, ObjectRange::Bytes(4, 1)).unwrap(),
+            (4, 1)
+        );
+        assert!(part_range(Some(&sizes), 5, ObjectRange::Part(3)).is_err());
+        assert!(part_range(None, 5, ObjectRange::Part(2)).is_err());
+        assert!(part_range(None, 5, ObjectRange::Bytes(4, 2)).is_err());
+        assert!(part_range(None, 5, ObjectRange::Bytes(0, 0)).is_err());
+    }
+
+    #[test]
+    fn computes_e_tags_as_s3() {
+        assert_eq!(e_tag(b""), "\"d41d8cd98f00b204e9800998ecf8427e\"");
+        let digests = [md5::compute(b""), md5::compute(b"")];
+        let e_tag = multipart_e_tag(&digests);
+        assert!(
+            e_tag.starts_with('"') && e_tag.ends_with("-2\""),
+            "{}",
+            e_tag
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_store_stores_objects() {
+        stores_objects(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn local_store_stores_objects() {
+        let root = test_dir("local-store-objects");
+        stores_objects(&LocalStore::new(root.clone())).await;
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
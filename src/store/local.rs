@@ -0,0 +1,511 @@
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::prelude::*;
+
+use super::{
+    Availability, GetObjectOutput, HeadObjectOutput, ListMultipartUploadsOutput, ListPartsOutput,
+    MultipartUploadInfo, ObjectAttributes, ObjectRange, ObjectStore, PartInfo, RestoreTier,
+    StoreFuture,
+};
+use crate::error::Error;
+
+/// The directory in each bucket which has the metadata of objects and multipart uploads
+const STORE_DIR: &str = ".s3ar-store";
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The store which keeps objects as files at `ROOT/BUCKET/KEY`,
+/// which is useful for local directories and NFS
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    root: PathBuf,
+    upload_count: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ObjectMetadata {
+    e_tag: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    part_sizes: Option<Vec<usize>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UploadMetadata {
+    key: String,
+    initiated: String,
+}
+
+impl LocalStore {
+    pub fn new(root: PathBuf) -> Self {
+        LocalStore {
+            inner: Arc::new(Inner {
+                root,
+                upload_count: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+impl Inner {
+    fn bucket_path(&self, bucket: &str) -> Result<PathBuf, Error> {
+        if bucket.is_empty() || bucket.contains('/') || bucket == "." || bucket == ".." {
+            return Err(format!("invalid bucket name for local store: {}", bucket).into());
+        }
+        Ok(self.root.join(bucket))
+    }
+
+    fn object_path(&self, bucket: &str, key: &str) -> Result<PathBuf, Error> {
+        let invalid = Path::new(key).components().any(|c| match c {
+            Component::Normal(name) => name == STORE_DIR,
+            _ => true,
+        });
+        if invalid || key.starts_with('/') {
+            return Err(format!("invalid key for local store: {}", key).into());
+        }
+        Ok(self.bucket_path(bucket)?.join(key))
+    }
+
+    fn metadata_path(&self, bucket: &str, key: &str) -> Result<PathBuf, Error> {
+        self.object_path(bucket, key)?;
+        let path = self
+            .bucket_path(bucket)?
+            .join(STORE_DIR)
+            .join("objects")
+            .join(key);
+        Ok(path.with_file_name(format!(
+            "{}.json",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        )))
+    }
+
+    fn uploads_path(&self, bucket: &str) -> Result<PathBuf, Error> {
+        Ok(self.bucket_path(bucket)?.join(STORE_DIR).join("uploads"))
+    }
+
+    fn upload_path(&self, bucket: &str, upload_id: &str) -> Result<PathBuf, Error> {
+        if upload_id.is_empty()
+            || !upload_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(format!("invalid upload ID: {}", upload_id).into());
+        }
+        Ok(self.uploads_path(bucket)?.join(upload_id))
+    }
+
+    /// Creates the temporary file which is renamed to the path once written
+    /// so that it won't be read half-written
+    async fn create_tmp_file(&self, bucket: &str) -> Result<(PathBuf, fs::File), Error> {
+        let tmp_dir = self.bucket_path(bucket)?.join(STORE_DIR).join("tmp");
+        fs::create_dir_all(&tmp_dir).await?;
+        let tmp_path = tmp_dir.join(self.unique_id());
+        let file = fs::File::create(&tmp_path).await?;
+        Ok((tmp_path, file))
+    }
+
+    async fn rename_tmp_file(&self, tmp_path: &Path, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        fs::rename(tmp_path, path).await?;
+        Ok(())
+    }
+
+    async fn write_file(&self, bucket: &str, path: &Path, body: &[u8]) -> Result<(), Error> {
+        let (tmp_path, mut file) = self.create_tmp_file(bucket).await?;
+        file.write_all(body).await?;
+        drop(file);
+        self.rename_tmp_file(&tmp_path, path).await
+    }
+
+    fn unique_id(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let count = self.upload_count.fetch_add(1, Ordering::SeqCst);
+        format!("{:x}-{:x}-{:x}", now.as_nanos(), std::process::id(), count)
+    }
+
+    async fn create_multipart_upload(&self, bucket: String, key: String) -> Result<String, Error> {
+        self.object_path(&bucket, &key)?;
+        let upload_id = self.unique_id();
+        let upload_path = self.upload_path(&bucket, &upload_id)?;
+        fs::create_dir_all(&upload_path).await?;
+        let metadata = UploadMetadata {
+            key,
+            initiated: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        };
+        let metadata = serde_json::to_vec(&metadata)?;
+        self.write_file(&bucket, &upload_path.join("upload.json"), &metadata)
+            .await?;
+        Ok(upload_id)
+    }
+
+    async fn upload_metadata(
+        &self,
+        bucket: &str,
+        upload_id: &str,
+    ) -> Result<UploadMetadata, Error> {
+        let path = self.upload_path(bucket, upload_id)?.join("upload.json");
+        let metadata = match fs::read(&path).await {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::not_found(format!("no such upload: {}", upload_id)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(serde_json::from_slice(&metadata)?)
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: String,
+        upload_id: String,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> Result<String, Error> {
+        if part_number < 1 {
+            return Err(format!("invalid part number: {}", part_number).into());
+        }
+        self.upload_metadata(&bucket, &upload_id).await?;
+        let path = self
+            .upload_path(&bucket, &upload_id)?
+            .join(part_number.to_string());
+        self.write_file(&bucket, &path, &body).await?;
+        Ok(super::e_tag(&body))
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        bucket: String,
+        key: String,
+        upload_id: String,
+        parts: Vec<(i64, String)>,
+    ) -> Result<(), Error> {
+        let upload_path = self.upload_path(&bucket, &upload_id)?;
+        let upload = self.upload_metadata(&bucket, &upload_id).await?;
+        if upload.key != key {
+            return Err(format!("upload {} is not of {}", upload_id, key).into());
+        }
+        let (tmp_path, mut file) = self.create_tmp_file(&bucket).await?;
+        let mut part_digests = Vec::new();
+        let mut part_sizes = Vec::new();
+        for (part_number, e_tag) in parts {
+            let part = fs::read(upload_path.join(part_number.to_string())).await?;
+            if super::e_tag(&part) != e_tag {
+                fs::remove_file(&tmp_path).await?;
+                return Err(
+                    format!("part {} doesn't match the ETag {}", part_number, e_tag).into(),
+                );
+            }
+            part_digests.push(md5::compute(&part));
+            part_sizes.push(part.len());
+            file.write_all(&part).await?;
+        }
+        drop(file);
+        let metadata = ObjectMetadata {
+            e_tag: super::multipart_e_tag(&part_digests),
+            part_sizes: Some(part_sizes),
+        };
+        let metadata = serde_json::to_vec(&metadata)?;
+        self.rename_tmp_file(&tmp_path, &self.object_path(&bucket, &key)?)
+            .await?;
+        self.write_file(&bucket, &self.metadata_path(&bucket, &key)?, &metadata)
+            .await?;
+        fs::remove_dir_all(&upload_path).await?;
+        Ok(())
+    }
+
+    async fn write_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: &[u8],
+        metadata: &ObjectMetadata,
+    ) -> Result<(), Error> {
+        let metadata = serde_json::to_vec(metadata)?;
+        self.write_file(bucket, &self.object_path(bucket, key)?, body)
+            .await?;
+        self.write_file(bucket, &self.metadata_path(bucket, key)?, &metadata)
+            .await?;
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(&self, bucket: String, upload_id: String) -> Result<(), Error> {
+        match fs::remove_dir_all(self.upload_path(&bucket, &upload_id)?).await {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(Error::not_found(format!("no such upload: {}", upload_id)))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Lists all uploads at once, so the output is never truncated
+    async fn list_multipart_uploads(
+        &self,
+        bucket: String,
+        prefix: String,
+    ) -> Result<ListMultipartUploadsOutput, Error> {
+        let mut uploads = Vec::new();
+        let mut dir = match fs::read_dir(self.uploads_path(&bucket)?).await {
+            Ok(dir) => dir,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(ListMultipartUploadsOutput {
+                    uploads,
+                    next_markers: None,
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = dir.next_entry().await? {
+            let upload_id = entry.file_name().to_string_lossy().into_owned();
+            let upload = self.upload_metadata(&bucket, &upload_id).await?;
+            if upload.key.starts_with(&prefix) {
+                uploads.push(MultipartUploadInfo {
+                    key: upload.key,
+                    upload_id,
+                    initiated: upload.initiated,
+                });
+            }
+        }
+        uploads.sort_by(|a, b| (&a.key, &a.upload_id).cmp(&(&b.key, &b.upload_id)));
+        Ok(ListMultipartUploadsOutput {
+            uploads,
+            next_markers: None,
+        })
+    }
+
+    /// Lists all parts at once, so the output is never truncated
+    async fn list_parts(
+        &self,
+        bucket: String,
+        upload_id: String,
+        marker: Option<i64>,
+    ) -> Result<ListPartsOutput, Error> {
+        self.upload_metadata(&bucket, &upload_id).await?;
+        let mut parts = Vec::new();
+        let mut dir = fs::read_dir(self.upload_path(&bucket, &upload_id)?).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let part_number = match entry.file_name().to_string_lossy().parse::<i64>() {
+                Ok(part_number) if part_number > marker.unwrap_or(0) => part_number,
+                _ => continue,
+            };
+            let part = fs::read(entry.path()).await?;
+            parts.push(PartInfo {
+                part_number,
+                e_tag: super::e_tag(&part),
+                size: part.len(),
+            });
+        }
+        parts.sort_by_key(|part| part.part_number);
+        Ok(ListPartsOutput {
+            parts,
+            next_marker: None,
+        })
+    }
+
+    async fn put_object(&self, bucket: String, key: String, body: Vec<u8>) -> Result<(), Error> {
+        let metadata = ObjectMetadata {
+            e_tag: super::e_tag(&body),
+            part_sizes: None,
+        };
+        self.write_object(&bucket, &key, &body, &metadata).await
+    }
+
+    async fn object_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<Option<ObjectMetadata>, Error> {
+        match fs::read(self.metadata_path(bucket, key)?).await {
+            Ok(metadata) => Ok(Some(serde_json::from_slice(&metadata)?)),
+            // the file put into the directory by other than s3ar
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_object(
+        &self,
+        bucket: String,
+        key: String,
+        range: ObjectRange,
+    ) -> Result<GetObjectOutput, Error> {
+        let path = self.object_path(&bucket, &key)?;
+        let mut file = match fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::not_found(format!("no such key: {}", key)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata().await?.len() as usize;
+        let metadata = self.object_metadata(&bucket, &key).await?;
+        let part_sizes = metadata.and_then(|metadata| metadata.part_sizes);
+        let (offset, content_length) = super::part_range(part_sizes.as_deref(), len, range)?;
+        file.seek(SeekFrom::Start(offset as u64)).await?;
+        let body = stream::try_unfold((file, content_length), |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return Ok(None);
+            }
+            let mut buf = vec![0; std::cmp::min(remaining, READ_BUFFER_SIZE)];
+            file.read_exact(&mut buf).await?;
+            let remaining = remaining - buf.len();
+            Ok(Some((buf, (file, remaining))))
+        })
+        .boxed();
+        Ok(GetObjectOutput {
+            body,
+            content_length,
+            parts_count: super::parts_count(part_sizes.as_deref(), range),
+        })
+    }
+
+    async fn head_object(
+        &self,
+        bucket: String,
+        key: String,
+    ) -> Result<Option<HeadObjectOutput>, Error> {
+        let path = self.object_path(&bucket, &key)?;
+        let file_metadata = match fs::metadata(&path).await {
+            Ok(file_metadata) if file_metadata.is_file() => file_metadata,
+            Ok(_) => return Ok(None),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let metadata = self.object_metadata(&bucket, &key).await?;
+        Ok(Some(HeadObjectOutput {
+            content_length: file_metadata.len() as usize,
+            e_tag: metadata.map(|metadata| metadata.e_tag),
+            availability: Availability::Available,
+        }))
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn create_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        _attributes: &ObjectAttributes,
+    ) -> StoreFuture<String> {
+        let inner = self.inner.clone();
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        async move { inner.create_multipart_upload(bucket, key).await }.boxed()
+    }
+
+    fn upload_part(
+        &self,
+        bucket: &str,
+        _key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> StoreFuture<String> {
+        let inner = self.inner.clone();
+        let (bucket, upload_id) = (bucket.to_string(), upload_id.to_string());
+        async move {
+            inner
+                .upload_part(bucket, upload_id, part_number, body)
+                .await
+        }
+        .boxed()
+    }
+
+    fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<(i64, String)>,
+    ) -> StoreFuture<()> {
+        let inner = self.inner.clone();
+        let (bucket, key, upload_id) = (bucket.to_string(), key.to_string(), upload_id.to_string());
+        async move {
+            inner
+                .complete_multipart_upload(bucket, key, upload_id, parts)
+                .await
+        }
+        .boxed()
+    }
+
+    fn abort_multipart_upload(&self, bucket: &str, _key: &str, upload_id: &str) -> StoreFuture<()> {
+        let inner = self.inner.clone();
+        let (bucket, upload_id) = (bucket.to_string(), upload_id.to_string());
+        async move { inner.abort_multipart_upload(bucket, upload_id).await }.boxed()
+    }
+
+    fn list_multipart_uploads(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        _markers: Option<(String, String)>,
+    ) -> StoreFuture<ListMultipartUploadsOutput> {
+        let inner = self.inner.clone();
+        let (bucket, prefix) = (bucket.to_string(), prefix.to_string());
+        async move { inner.list_multipart_uploads(bucket, prefix).await }.boxed()
+    }
+
+    fn list_parts(
+        &self,
+        bucket: &str,
+        _key: &str,
+        upload_id: &str,
+        marker: Option<i64>,
+    ) -> StoreFuture<ListPartsOutput> {
+        let inner = self.inner.clone();
+        let (bucket, upload_id) = (bucket.to_string(), upload_id.to_string());
+        async move { inner.list_parts(bucket, upload_id, marker).await }.boxed()
+    }
+
+    fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+        _attributes: &ObjectAttributes,
+    ) -> StoreFuture<()> {
+        let inner = self.inner.clone();
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        async move { inner.put_object(bucket, key, body).await }.boxed()
+    }
+
+    fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: ObjectRange,
+    ) -> StoreFuture<GetObjectOutput> {
+        let inner = self.inner.clone();
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        async move { inner.get_object(bucket, key, range).await }.boxed()
+    }
+
+    fn head_object(&self, bucket: &str, key: &str) -> StoreFuture<Option<HeadObjectOutput>> {
+        let inner = self.inner.clone();
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        async move { inner.head_object(bucket, key).await }.boxed()
+    }
+
+    fn restore_object(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        _tier: RestoreTier,
+        _days: i64,
+    ) -> StoreFuture<()> {
+        future::ok(()).boxed()
+    }
+}
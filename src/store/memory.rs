@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use futures::prelude::*;
+
+use super::{
+    Availability, GetObjectOutput, HeadObjectOutput, ListMultipartUploadsOutput, ListPartsOutput,
+    MultipartUploadInfo, ObjectAttributes, ObjectRange, ObjectStore, PartInfo, RestoreTier,
+    StoreFuture,
+};
+use crate::error::Error;
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    objects: HashMap<(String, String), MemoryObject>,
+    uploads: BTreeMap<(String, String, String), MemoryUpload>,
+    upload_count: usize,
+}
+
+#[derive(Debug, Clone)]
+struct MemoryObject {
+    body: Arc<Vec<u8>>,
+    e_tag: String,
+    part_sizes: Option<Vec<usize>>,
+}
+
+#[derive(Debug)]
+struct MemoryUpload {
+    initiated: String,
+    parts: BTreeMap<i64, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn with_inner<T, F>(&self, f: F) -> StoreFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Inner) -> Result<T, Error>,
+    {
+        let result = f(&mut self.inner.lock().unwrap());
+        future::ready(result).boxed()
+    }
+}
+
+fn upload_key(bucket: &str, key: &str, upload_id: &str) -> (String, String, String) {
+    (bucket.to_string(), key.to_string(), upload_id.to_string())
+}
+
+fn no_such_upload(upload_id: &str) -> Error {
+    Error::not_found(format!("no such upload: {}", upload_id))
+}
+
+impl ObjectStore for MemoryStore {
+    fn create_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        _attributes: &ObjectAttributes,
+    ) -> StoreFuture<String> {
+        self.with_inner(|inner| {
+            inner.upload_count += 1;
+            let upload_id = format!("{:x}", inner.upload_count);
+            let upload = MemoryUpload {
+                initiated: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                parts: BTreeMap::new(),
+            };
+            inner
+                .uploads
+                .insert(upload_key(bucket, key, &upload_id), upload);
+            Ok(upload_id)
+        })
+    }
+
+    fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> StoreFuture<String> {
+        self.with_inner(|inner| {
+            let upload = inner
+                .uploads
+                .get_mut(&upload_key(bucket, key, upload_id))
+                .ok_or_else(|| no_such_upload(upload_id))?;
+            let e_tag = super::e_tag(&body);
+            upload.parts.insert(part_number, body);
+            Ok(e_tag)
+        })
+    }
+
+    fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<(i64, String)>,
+    ) -> StoreFuture<()> {
+        self.with_inner(|inner| {
+            let upload_key = upload_key(bucket, key, upload_id);
+            let upload = inner
+                .uploads
+                .get(&upload_key)
+                .ok_or_else(|| no_such_upload(upload_id))?;
+            let mut body = Vec::new();
+            let mut part_digests = Vec::new();
+            let mut part_sizes = Vec::new();
+            for (part_number, e_tag) in parts {
+                let part = upload
+                    .parts
+                    .get(&part_number)
+                    .filter(|part| super::e_tag(part) == e_tag)
+                    .ok_or_else(|| format!("invalid part: {}", part_number))?;
+                part_digests.push(md5::compute(part));
+                part_sizes.push(part.len());
+                body.extend_from_slice(part);
+            }
+            let object = MemoryObject {
+                body: Arc::new(body),
+                e_tag: super::multipart_e_tag(&part_digests),
+                part_sizes: Some(part_sizes),
+            };
+            inner
+                .objects
+                .insert((bucket.to_string(), key.to_string()), object);
+            inner.uploads.remove(&upload_key);
+            Ok(())
+        })
+    }
+
+    fn abort_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str) -> StoreFuture<()> {
+        self.with_inner(|inner| {
+            inner
+                .uploads
+                .remove(&upload_key(bucket, key, upload_id))
+                .map(|_| ())
+                .ok_or_else(|| no_such_upload(upload_id))
+        })
+    }
+
+    /// Lists all uploads at once, so the output is never truncated
+    fn list_multipart_uploads(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        _markers: Option<(String, String)>,
+    ) -> StoreFuture<ListMultipartUploadsOutput> {
+        self.with_inner(|inner| {
+            let uploads = inner
+                .uploads
+                .iter()
+                .filter(|((b, key, _), _)| b == bucket && key.starts_with(prefix))
+                .map(|((_, key, upload_id), upload)| MultipartUploadInfo {
+                    key: key.clone(),
+                    upload_id: upload_id.clone(),
+                    initiated: upload.initiated.clone(),
+                })
+                .collect();
+            Ok(ListMultipartUploadsOutput {
+                uploads,
+                next_markers: None,
+            })
+        })
+    }
+
+    /// Lists all parts at once, so the output is never truncated
+    fn list_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        marker: Option<i64>,
+    ) -> StoreFuture<ListPartsOutput> {
+        self.with_inner(|inner| {
+            let upload = inner
+                .uploads
+                .get(&upload_key(bucket, key, upload_id))
+                .ok_or_else(|| no_such_upload(upload_id))?;
+            let parts = upload
+                .parts
+                .range(marker.unwrap_or(0) + 1..)
+                .map(|(&part_number, part)| PartInfo {
+                    part_number,
+                    e_tag: super::e_tag(part),
+                    size: part.len(),
+                })
+                .collect();
+            Ok(ListPartsOutput {
+                parts,
+                next_marker: None,
+            })
+        })
+    }
+
+    fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+        _attributes: &ObjectAttributes,
+    ) -> StoreFuture<()> {
+        self.with_inner(|inner| {
+            let object = MemoryObject {
+                e_tag: super::e_tag(&body),
+                body: Arc::new(body),
+                part_sizes: None,
+            };
+            inner
+                .objects
+                .insert((bucket.to_string(), key.to_string()), object);
+            Ok(())
+        })
+    }
+
+    fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: ObjectRange,
+    ) -> StoreFuture<GetObjectOutput> {
+        self.with_inner(|inner| {
+            let object = inner
+                .objects
+                .get(&(bucket.to_string(), key.to_string()))
+                .ok_or_else(|| Error::not_found(format!("no such key: {}", key)))?;
+            let part_sizes = object.part_sizes.as_deref();
+            let (offset, content_length) = super::part_range(part_sizes, object.body.len(), range)?;
+            let body = object.body[offset..offset + content_length].to_vec();
+            Ok(GetObjectOutput {
+                body: stream::once(future::ok(body)).boxed(),
+                content_length,
+                parts_count: super::parts_count(part_sizes, range),
+            })
+        })
+    }
+
+    fn head_object(&self, bucket: &str, key: &str) -> StoreFuture<Option<HeadObjectOutput>> {
+        self.with_inner(|inner| {
+            let object = inner.objects.get(&(bucket.to_string(), key.to_string()));
+            Ok(object.map(|object| HeadObjectOutput {
+                content_length: object.body.len(),
+                e_tag: Some(object.e_tag.clone()),
+                availability: Availability::Available,
+            }))
+        })
+    }
+
+    fn restore_object(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        _tier: RestoreTier,
+        _days: i64,
+    ) -> StoreFuture<()> {
+        future::ok(()).boxed()
+    }
+}
@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use futures::compat::*;
+use futures::prelude::*;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    AbortMultipartUploadError, AbortMultipartUploadRequest, CompleteMultipartUploadRequest,
+    CompletedMultipartUpload, CompletedPart, CreateMultipartUploadRequest, GetObjectError,
+    GetObjectRequest, GlacierJobParameters, HeadObjectError, HeadObjectRequest,
+    ListMultipartUploadsRequest, ListPartsRequest, PutObjectRequest, RestoreObjectError,
+    RestoreObjectRequest, RestoreRequest, S3Client, UploadPartRequest, S3,
+};
+
+use super::{
+    Availability, GetObjectOutput, HeadObjectOutput, ListMultipartUploadsOutput, ListPartsOutput,
+    MultipartUploadInfo, ObjectAttributes, ObjectRange, ObjectStore, PartInfo, RestoreTier,
+    StoreFuture,
+};
+use crate::error::Error;
+
+/// The server-side encryption which S3 applies to the objects
+///
+/// The ETags of the objects encrypted by SSE-KMS or SSE-C aren't the MD5 of them,
+/// so that resumed uploads upload such objects again.
+#[derive(Debug, Clone)]
+pub enum ServerSideEncryption {
+    S3,
+    Kms(Option<String>),
+    /// SSE-C by the customer key in 32 bytes, which every request to the objects must carry
+    Customer(Vec<u8>),
+}
+
+impl ServerSideEncryption {
+    pub fn customer_key_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let key = std::fs::read(path)?;
+        let key = if key.len() == SSE_CUSTOMER_KEY_SIZE {
+            key
+        } else {
+            let text = String::from_utf8_lossy(&key);
+            base64::decode(text.trim())
+                .ok()
+                .filter(|key| key.len() == SSE_CUSTOMER_KEY_SIZE)
+                .ok_or("SSE-C key must be 32 bytes or base64 of them")?
+        };
+        Ok(ServerSideEncryption::Customer(key))
+    }
+}
+
+const SSE_CUSTOMER_KEY_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Default)]
+struct SseHeaders {
+    server_side_encryption: Option<String>,
+    ssekms_key_id: Option<String>,
+    sse_customer_algorithm: Option<String>,
+    sse_customer_key: Option<String>,
+    sse_customer_key_md5: Option<String>,
+}
+
+impl From<ServerSideEncryption> for SseHeaders {
+    fn from(sse: ServerSideEncryption) -> Self {
+        match sse {
+            ServerSideEncryption::S3 => SseHeaders {
+                server_side_encryption: Some("AES256".to_string()),
+                ..Default::default()
+            },
+            ServerSideEncryption::Kms(key_id) => SseHeaders {
+                server_side_encryption: Some("aws:kms".to_string()),
+                ssekms_key_id: key_id,
+                ..Default::default()
+            },
+            ServerSideEncryption::Customer(key) => SseHeaders {
+                sse_customer_algorithm: Some("AES256".to_string()),
+                sse_customer_key: Some(base64::encode(&key)),
+                sse_customer_key_md5: Some(base64::encode(&md5::compute(&key).0)),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct S3Store {
+    s3_client: S3Client,
+    sse: SseHeaders,
+}
+
+impl S3Store {
+    pub fn new(s3_client: S3Client) -> Self {
+        Self {
+            s3_client,
+            sse: SseHeaders::default(),
+        }
+    }
+
+    pub fn with_server_side_encryption(self, sse: ServerSideEncryption) -> Self {
+        Self {
+            sse: sse.into(),
+            ..self
+        }
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn create_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        attributes: &ObjectAttributes,
+    ) -> StoreFuture<String> {
+        let request = CreateMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_type: attributes.content_type.clone(),
+            storage_class: attributes.storage_class.clone(),
+            tagging: tagging(&attributes.tags),
+            metadata: metadata(&attributes.metadata),
+            server_side_encryption: self.sse.server_side_encryption.clone(),
+            ssekms_key_id: self.sse.ssekms_key_id.clone(),
+            sse_customer_algorithm: self.sse.sse_customer_algorithm.clone(),
+            sse_customer_key: self.sse.sse_customer_key.clone(),
+            sse_customer_key_md5: self.sse.sse_customer_key_md5.clone(),
+            ..Default::default()
+        };
+        self.s3_client
+            .create_multipart_upload(request)
+            .compat()
+            .map_err(Error::from)
+            .and_then(|output| {
+                future::ready(
+                    output
+                        .upload_id
+                        .ok_or_else(|| "no upload_id in response".into()),
+                )
+            })
+            .boxed()
+    }
+
+    fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> StoreFuture<String> {
+        let request = UploadPartRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_length: Some(body.len() as i64),
+            content_md5: Some(base64::encode(&md5::compute(&body).0)),
+            body: Some(body.into()),
+            part_number,
+            upload_id: upload_id.to_string(),
+            sse_customer_algorithm: self.sse.sse_customer_algorithm.clone(),
+            sse_customer_key: self.sse.sse_customer_key.clone(),
+            sse_customer_key_md5: self.sse.sse_customer_key_md5.clone(),
+            ..Default::default()
+        };
+        self.s3_client
+            .upload_part(request)
+            .compat()
+            .map_err(Error::from)
+            .and_then(|output| {
+                future::ready(output.e_tag.ok_or_else(|| "no ETag in response".into()))
+            })
+            .boxed()
+    }
+
+    fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<(i64, String)>,
+    ) -> StoreFuture<()> {
+        let parts = parts
+            .into_iter()
+            .map(|(part_number, e_tag)| CompletedPart {
+                e_tag: Some(e_tag),
+                part_number: Some(part_number),
+            })
+            .collect();
+        let request = CompleteMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            upload_id: upload_id.to_string(),
+            ..Default::default()
+        };
+        self.s3_client
+            .complete_multipart_upload(request)
+            .compat()
+            .map_ok(|_| ())
+            .map_err(Error::from)
+            .boxed()
+    }
+
+    fn abort_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str) -> StoreFuture<()> {
+        let request = AbortMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            ..Default::default()
+        };
+        self.s3_client
+            .abort_multipart_upload(request)
+            .compat()
+            .map_ok(|_| ())
+            .map_err(not_found_with_upload_id(upload_id.to_string()))
+            .boxed()
+    }
+
+    fn list_multipart_uploads(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        markers: Option<(String, String)>,
+    ) -> StoreFuture<ListMultipartUploadsOutput> {
+        let (key_marker, upload_id_marker) = match markers {
+            Some((key_marker, upload_id_marker)) => (Some(key_marker), Some(upload_id_marker)),
+            None => (None, None),
+        };
+        let request = ListMultipartUploadsRequest {
+            bucket: bucket.to_string(),
+            prefix: Some(prefix.to_string()),
+            key_marker,
+            upload_id_marker,
+            ..Default::default()
+        };
+        self.s3_client
+            .list_multipart_uploads(request)
+            .compat()
+            .map_err(Error::from)
+            .map_ok(|output| {
+                let uploads = output
+                    .uploads
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|u| match (u.key, u.upload_id, u.initiated) {
+                        (Some(key), Some(upload_id), Some(initiated)) => {
+                            Some(MultipartUploadInfo {
+                                key,
+                                upload_id,
+                                initiated,
+                            })
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                let next_markers = match (
+                    output.is_truncated,
+                    output.next_key_marker,
+                    output.next_upload_id_marker,
+                ) {
+                    (Some(true), Some(key_marker), upload_id_marker) => {
+                        Some((key_marker, upload_id_marker.unwrap_or_default()))
+                    }
+                    _ => None,
+                };
+                ListMultipartUploadsOutput {
+                    uploads,
+                    next_markers,
+                }
+            })
+            .boxed()
+    }
+
+    fn list_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        marker: Option<i64>,
+    ) -> StoreFuture<ListPartsOutput> {
+        let request = ListPartsRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            part_number_marker: marker,
+            upload_id: upload_id.to_string(),
+            ..Default::default()
+        };
+        self.s3_client
+            .list_parts(request)
+            .compat()
+            .map_err(Error::from)
+            .map_ok(|output| {
+                let parts = output
+                    .parts
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|part| match (part.part_number, part.e_tag, part.size) {
+                        (Some(part_number), Some(e_tag), Some(size)) => Some(PartInfo {
+                            part_number,
+                            e_tag,
+                            size: size as usize,
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+                let next_marker = if output.is_truncated.unwrap_or(false) {
+                    output.next_part_number_marker
+                } else {
+                    None
+                };
+                ListPartsOutput { parts, next_marker }
+            })
+            .boxed()
+    }
+
+    fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+        attributes: &ObjectAttributes,
+    ) -> StoreFuture<()> {
+        let request = PutObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_length: Some(body.len() as i64),
+            content_md5: Some(base64::encode(&md5::compute(&body).0)),
+            body: Some(body.into()),
+            content_type: attributes.content_type.clone(),
+            storage_class: attributes.storage_class.clone(),
+            tagging: tagging(&attributes.tags),
+            metadata: metadata(&attributes.metadata),
+            server_side_encryption: self.sse.server_side_encryption.clone(),
+            ssekms_key_id: self.sse.ssekms_key_id.clone(),
+            sse_customer_algorithm: self.sse.sse_customer_algorithm.clone(),
+            sse_customer_key: self.sse.sse_customer_key.clone(),
+            sse_customer_key_md5: self.sse.sse_customer_key_md5.clone(),
+            ..Default::default()
+        };
+        self.s3_client
+            .put_object(request)
+            .compat()
+            .map_ok(|_| ())
+            .map_err(Error::from)
+            .boxed()
+    }
+
+    fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: ObjectRange,
+    ) -> StoreFuture<GetObjectOutput> {
+        let (part_number, range) = match range {
+            ObjectRange::Whole => (None, None),
+            ObjectRange::Part(part_number) => (Some(part_number), None),
+            ObjectRange::Bytes(offset, len) => {
+                (None, Some(format!("bytes={}-{}", offset, offset + len - 1)))
+            }
+        };
+        let request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            part_number,
+            range,
+            sse_customer_algorithm: self.sse.sse_customer_algorithm.clone(),
+            sse_customer_key: self.sse.sse_customer_key.clone(),
+            sse_customer_key_md5: self.sse.sse_customer_key_md5.clone(),
+            ..Default::default()
+        };
+        self.s3_client
+            .get_object(request)
+            .compat()
+            .map_err(not_found_with_key(key.to_string()))
+            .and_then(|output| {
+                let result = (|| {
+                    let content_length = output.content_length.ok_or("no content length header")?;
+                    let body = output
+                        .body
+                        .ok_or("no body")?
+                        .compat()
+                        .map_ok(|bytes| bytes.to_vec())
+                        .boxed();
+                    Ok(GetObjectOutput {
+                        body,
+                        content_length: content_length as usize,
+                        parts_count: output.parts_count,
+                    })
+                })();
+                future::ready(result)
+            })
+            .boxed()
+    }
+
+    fn head_object(&self, bucket: &str, key: &str) -> StoreFuture<Option<HeadObjectOutput>> {
+        let request = HeadObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            sse_customer_algorithm: self.sse.sse_customer_algorithm.clone(),
+            sse_customer_key: self.sse.sse_customer_key.clone(),
+            sse_customer_key_md5: self.sse.sse_customer_key_md5.clone(),
+            ..Default::default()
+        };
+        self.s3_client
+            .head_object(request)
+            .compat()
+            .map(ok_if_not_found)
+            .map_ok(|output| {
+                output.map(|output| HeadObjectOutput {
+                    content_length: output.content_length.unwrap_or_default() as usize,
+                    availability: availability(
+                        output.storage_class.as_deref(),
+                        output.restore.as_deref(),
+                    ),
+                    e_tag: output.e_tag,
+                })
+            })
+            .boxed()
+    }
+
+    fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        tier: RestoreTier,
+        days: i64,
+    ) -> StoreFuture<()> {
+        let request = RestoreObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            restore_request: Some(RestoreRequest {
+                days: Some(days),
+                glacier_job_parameters: Some(GlacierJobParameters {
+                    tier: tier.to_string(),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        self.s3_client
+            .restore_object(request)
+            .compat()
+            .map(ok_if_in_progress)
+            .boxed()
+    }
+}
+
+fn availability(storage_class: Option<&str>, restore: Option<&str>) -> Availability {
+    let archived = matches!(storage_class, Some("GLACIER") | Some("DEEP_ARCHIVE"));
+    match restore {
+        _ if !archived => Availability::Available,
+        None => Availability::Archived,
+        Some(restore) if restore.contains("ongoing-request=\"true\"") => Availability::Restoring,
+        Some(_) => Availability::Available,
+    }
+}
+
+/// Turns the conflict of RestoreObject into success,
+/// which means that the restore of the object is in progress already
+fn ok_if_in_progress<T>(result: Result<T, RusotoError<RestoreObjectError>>) -> Result<(), Error> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(RusotoError::Unknown(ref res)) if res.status.as_u16() == 409 => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The characters which are escaped in the tags, leaving the unreserved ones of RFC 3986
+const TAG_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+fn tagging(tags: &[(String, String)]) -> Option<String> {
+    if tags.is_empty() {
+        return None;
+    }
+    let pairs: Vec<_> = tags
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(key, TAG_ENCODE_SET),
+                utf8_percent_encode(value, TAG_ENCODE_SET)
+            )
+        })
+        .collect();
+    Some(pairs.join("&"))
+}
+
+fn metadata(metadata: &[(String, String)]) -> Option<HashMap<String, String>> {
+    if metadata.is_empty() {
+        return None;
+    }
+    Some(metadata.iter().cloned().collect())
+}
+
+/// Tells the key of the missing object, which the error of GetObject doesn't
+fn not_found_with_key(key: String) -> impl FnOnce(RusotoError<GetObjectError>) -> Error {
+    move |e| match e {
+        RusotoError::Service(GetObjectError::NoSuchKey(_)) => {
+            Error::not_found(format!("no such key: {}", key))
+        }
+        e => e.into(),
+    }
+}
+
+fn not_found_with_upload_id(
+    upload_id: String,
+) -> impl FnOnce(RusotoError<AbortMultipartUploadError>) -> Error {
+    move |e| match e {
+        RusotoError::Service(AbortMultipartUploadError::NoSuchUpload(_)) => {
+            Error::not_found(format!("no such upload: {}", upload_id))
+        }
+        e => e.into(),
+    }
+}
+
+/// Turns the not found error of HeadObject into `None`
+/// so that it won't be retried as a failure
+fn ok_if_not_found<T>(result: Result<T, RusotoError<HeadObjectError>>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(output) => Ok(Some(output)),
+        Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(None),
+        Err(RusotoError::Unknown(ref res)) if res.status.as_u16() == 404 => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn tells_missing_keys_and_uploads() {
+        let e = RusotoError::Service(GetObjectError::NoSuchKey("missing".to_string()));
+        let e = not_found_with_key("a".to_string())(e);
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        let e = RusotoError::Service(AbortMultipartUploadError::NoSuchUpload(
+            "missing".to_string(),
+        ));
+        let e = not_found_with_upload_id("u".to_string())(e);
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,115 @@
+//! The fixtures of the archives shared by the tests of the executors
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use tokio::runtime::Runtime;
+
+use crate::create::{ArchiveCreate, CreateExecutor};
+use crate::encryption::{KeyProvider, MasterKeyProvider};
+use crate::extract::{ArchiveExtract, ExtractExecutor};
+use crate::store::ObjectStore;
+use crate::utils::test_dir;
+
+pub const BUCKET: &str = "bucket";
+pub const PREFIX: &str = "archive/";
+pub const RANDOM_PATH: &str = "dir/sub/random.bin";
+
+pub fn run<F: Future>(f: F) -> F::Output {
+    Runtime::new().unwrap().block_on(f)
+}
+
+/// Bytes which never shrink by compression
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut x: u32 = 2_463_534_242;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect()
+}
+
+pub fn contents() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("empty", Vec::new()),
+        ("small.txt", b"small".to_vec()),
+        ("dir/text.txt", "s3ar ".repeat(1000).into_bytes()),
+        (RANDOM_PATH, random_bytes(5000)),
+    ]
+}
+
+pub fn write_contents(dir: &Path) {
+    for (path, content) in contents() {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+}
+
+pub fn assert_contents(dir: &Path) {
+    for (path, content) in contents() {
+        assert_eq!(fs::read(dir.join(path)).unwrap(), content, "{}", path);
+    }
+}
+
+pub fn archive_create(source: &Path) -> ArchiveCreate {
+    ArchiveCreate {
+        part_size: 1024,
+        put_threshold: 0,
+        directory: Some(source.to_path_buf()),
+        s3_bucket: BUCKET.to_string(),
+        s3_prefix: PREFIX.to_string(),
+        files: vec!["dir".into(), "empty".into(), "small.txt".into()],
+        ..Default::default()
+    }
+}
+
+pub fn archive_extract(target: &Path) -> ArchiveExtract {
+    ArchiveExtract {
+        directory: Some(target.to_path_buf()),
+        s3_bucket: BUCKET.to_string(),
+        s3_prefix: PREFIX.to_string(),
+        range_size: 1000,
+        ..Default::default()
+    }
+}
+
+pub fn key_provider() -> Arc<dyn KeyProvider> {
+    Arc::new(MasterKeyProvider::new(&[7; 32]).unwrap())
+}
+
+pub async fn round_trip(
+    name: &str,
+    store: Arc<dyn ObjectStore>,
+    archive: impl FnOnce(ArchiveCreate) -> ArchiveCreate,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+) {
+    let source = test_dir(&format!("{}-source", name));
+    let target = test_dir(&format!("{}-target", name));
+    write_contents(&source);
+
+    let mut creator = CreateExecutor::new(store.clone());
+    let mut extractor = ExtractExecutor::new(store);
+    if let Some(key_provider) = key_provider {
+        creator = creator.with_key_provider(key_provider.clone());
+        extractor = extractor.with_key_provider(key_provider);
+    }
+    let archive = archive(archive_create(&source));
+    creator.execute(archive).await.unwrap();
+    extractor.execute(archive_extract(&target)).await.unwrap();
+
+    assert_contents(&target);
+    assert!(!target.join(".s3ar-journal").exists());
+    fs::remove_dir_all(&source).unwrap();
+    fs::remove_dir_all(&target).unwrap();
+}
+
+pub async fn content_length(store: &dyn ObjectStore, key: &str) -> usize {
+    let output = store.head_object(BUCKET, key).await.unwrap();
+    output.expect("no object").content_length
+}
@@ -1,9 +1,34 @@
 use std::cmp;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
 use std::time::Duration;
 use std::future::Future;
 use tokio::time::delay_for;
 
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use super::progress::{Progress, Reporter};
+use super::Error;
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
 pub async fn with_retry<F, T, E, Fut>(
+    retry_max: u32,
+    wait_base: u32,
+    wait_max: u32,
+    f: F,
+) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+    F: FnMut() -> Fut,
+    E: fmt::Display,
+{
+    with_reported_retry(&Reporter::default(), retry_max, wait_base, wait_max, f).await
+}
+
+pub async fn with_reported_retry<F, T, E, Fut>(
+    reporter: &Reporter,
     retry_max: u32,
     wait_base: u32,
     wait_max: u32,
@@ -12,6 +37,7 @@ pub async fn with_retry<F, T, E, Fut>(
 where
     Fut: Future<Output = Result<T, E>>,
     F: FnMut() -> Fut,
+    E: fmt::Display,
 {
     let mut retry: u32 = 0;
     loop {
@@ -23,8 +49,46 @@ where
         if retry > retry_max {
             return Err(e);
         }
-        let wait = cmp::min(wait_max, wait_base.pow(retry));
-        //eprintln!("RETRY #{} waiting {}secs: {}", retry, wait, e);
-        delay_for(Duration::from_secs(wait as u64)).await;
+        let wait = Duration::from_secs(cmp::min(wait_max, wait_base.pow(retry)) as u64);
+        reporter.report(Progress::Retrying {
+            retry,
+            wait,
+            cause: e.to_string(),
+        });
+        delay_for(wait).await;
+    }
+}
+
+pub fn build_glob_set(patterns: &[String]) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| format!("invalid pattern: {}", e))?;
+        builder.add(glob);
     }
+    let glob_set = builder
+        .build()
+        .map_err(|e| format!("invalid pattern: {}", e))?;
+    Ok(glob_set)
+}
+
+/// Computes CRC32C of the file, which blocks the thread
+pub fn crc32c_of_file(path: &Path) -> Result<u32, Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+    let mut crc = 0;
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(crc);
+        }
+        crc = crc32c::crc32c_append(crc, &buf[..len]);
+    }
+}
+
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("s3ar-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
 }
@@ -0,0 +1,314 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use tokio::fs;
+use tokio::prelude::*;
+use tokio::task;
+
+use super::encryption::KeyProvider;
+use super::error::Error;
+use super::file_entry::{FileEntry, Kind};
+use super::key_resolver;
+use super::manifest;
+use super::store::ObjectStore;
+use super::utils::{crc32c_of_file, with_retry};
+
+#[derive(Debug, Clone)]
+pub struct ArchiveVerify {
+    pub file_concurrency: usize,
+    pub directory: Option<PathBuf>,
+    pub s3_bucket: String,
+    pub s3_prefix: String,
+}
+
+#[derive(Debug)]
+pub enum Mismatch {
+    Missing(String),
+    Extra(String),
+    Differs(String, String),
+    MissingObject(String),
+    DiffersObject(String, String),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Missing(path) => write!(f, "missing\t{}", path),
+            Mismatch::Extra(path) => write!(f, "extra\t{}", path),
+            Mismatch::Differs(path, reason) => write!(f, "differs\t{}\t{}", path, reason),
+            Mismatch::MissingObject(key) => write!(f, "missing-object\t{}", key),
+            Mismatch::DiffersObject(key, reason) => {
+                write!(f, "differs-object\t{}\t{}", key, reason)
+            }
+        }
+    }
+}
+
+pub type MismatchCallback = Arc<dyn Fn(&Mismatch) + Send + Sync>;
+
+pub struct VerifyExecutor {
+    store: Arc<dyn ObjectStore>,
+    pack_sizes: Mutex<HashMap<String, Option<usize>>>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    on_mismatch: Option<MismatchCallback>,
+}
+
+impl VerifyExecutor {
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            pack_sizes: Default::default(),
+            key_provider: None,
+            on_mismatch: None,
+        }
+    }
+
+    pub fn on_mismatch(mut self, callback: MismatchCallback) -> Self {
+        self.on_mismatch = Some(callback);
+        self
+    }
+
+    pub fn with_key_provider(mut self, key_provider: Arc<dyn KeyProvider>) -> Self {
+        self.key_provider = Some(key_provider);
+        self
+    }
+
+    pub async fn execute(
+        &self,
+        ArchiveVerify {
+            file_concurrency,
+            directory,
+            s3_bucket,
+            s3_prefix,
+        }: ArchiveVerify,
+    ) -> Result<(), Error> {
+        let directory = directory.unwrap_or_default();
+        let key_provider = self.key_provider.as_deref();
+        let manifest =
+            manifest::Reader::get(self.store.as_ref(), &s3_bucket, &s3_prefix, key_provider)
+                .await?;
+        let data_key = manifest.data_key().cloned();
+        let (header, entries) = manifest.into_parts();
+        let mut paths = HashSet::new();
+        let mut dirs = Vec::new();
+        let mut mismatch_count = 0;
+        entries
+            .map_ok(|entry| {
+                paths.insert(entry.path().to_string());
+                if entry.kind() == Kind::Directory {
+                    dirs.push(entry.path().to_string());
+                } else if header.version < 3 {
+                    // the legacy manifest has no directories but all files are under them
+                    if let Some(dir) = Path::new(entry.path()).parent() {
+                        dirs.push(dir.to_string_lossy().into_owned());
+                    }
+                }
+                let s3_bucket = s3_bucket.clone();
+                let s3_prefix = s3_prefix.clone();
+                let directory = &directory;
+                let data_key = data_key.as_ref();
+                async move {
+                    let mut mismatches = verify_local(directory, &entry).await?;
+                    if let Some(pack) = entry.pack() {
+                        let pack_key = key_resolver::pack_key(&s3_prefix, &pack.key);
+                        let mismatch = self.verify_pack(s3_bucket, pack_key, &entry).await?;
+                        mismatches.extend(mismatch);
+                    } else if entry.kind() == Kind::File {
+                        let source_key = key_resolver::data_key(&s3_prefix, entry.path(), data_key);
+                        let mismatch = self.verify_object(s3_bucket, source_key, &entry).await?;
+                        mismatches.extend(mismatch);
+                    }
+                    Ok(mismatches)
+                }
+            })
+            .try_buffer_unordered(file_concurrency)
+            .try_for_each(|mismatches| {
+                for mismatch in mismatches {
+                    self.report(&mismatch);
+                    mismatch_count += 1;
+                }
+                future::ok(())
+            })
+            .await?;
+
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs.iter().filter(|dir| !dir.is_empty()) {
+            let mut children = match fs::read_dir(directory.join(dir)).await {
+                Ok(children) => children,
+                // reported as missing or differing already
+                Err(_) => continue,
+            };
+            while let Some(child) = children.next_entry().await? {
+                let path = Path::new(dir).join(child.file_name());
+                let path = path.to_string_lossy();
+                if !paths.contains(path.as_ref()) {
+                    self.report(&Mismatch::Extra(path.into_owned()));
+                    mismatch_count += 1;
+                }
+            }
+        }
+
+        if mismatch_count > 0 {
+            let message = format!("{} mismatches found", mismatch_count);
+            return Err(Error::integrity(message));
+        }
+        Ok(())
+    }
+
+    fn report(&self, mismatch: &Mismatch) {
+        if let Some(callback) = &self.on_mismatch {
+            callback(mismatch);
+        }
+    }
+
+    async fn verify_object(
+        &self,
+        bucket: String,
+        key: String,
+        entry: &FileEntry,
+    ) -> Result<Option<Mismatch>, Error> {
+        let output = with_retry(10, 1, 5, || self.store.head_object(&bucket, &key)).await?;
+        let output = match output {
+            Some(output) => output,
+            None => return Ok(Some(Mismatch::MissingObject(key))),
+        };
+        let size = output.content_length;
+        if size != entry.object_size() {
+            let reason = format!("size {} != {}", size, entry.object_size());
+            return Ok(Some(Mismatch::DiffersObject(key, reason)));
+        }
+        Ok(None)
+    }
+
+    async fn verify_pack(
+        &self,
+        bucket: String,
+        key: String,
+        entry: &FileEntry,
+    ) -> Result<Option<Mismatch>, Error> {
+        let pack = entry.pack().ok_or("no pack in manifest")?;
+        let checked = self.pack_sizes.lock().unwrap().get(&key).cloned();
+        let size = match checked {
+            Some(Some(size)) => size,
+            Some(None) => return Ok(None),
+            None => {
+                let output = with_retry(10, 1, 5, || self.store.head_object(&bucket, &key)).await?;
+                let size = output.map(|output| output.content_length);
+                // another entry in the same pack may have inserted it concurrently
+                let first = self
+                    .pack_sizes
+                    .lock()
+                    .unwrap()
+                    .insert(key.clone(), size)
+                    .is_none();
+                match size {
+                    Some(size) => size,
+                    None if first => return Ok(Some(Mismatch::MissingObject(key))),
+                    None => return Ok(None),
+                }
+            }
+        };
+        if pack.offset + pack.len > size {
+            let reason = format!(
+                "{} at {}+{} beyond size {}",
+                entry.path(),
+                pack.offset,
+                pack.len,
+                size
+            );
+            return Ok(Some(Mismatch::DiffersObject(key, reason)));
+        }
+        Ok(None)
+    }
+}
+
+async fn verify_local(dir: &Path, entry: &FileEntry) -> Result<Vec<Mismatch>, Error> {
+    let path = entry.path().to_string();
+    let local_path = dir.join(&path);
+    // files and directories may be archived through symlinks with --follow-symlinks
+    let metadata = match entry.kind() {
+        Kind::Symlink | Kind::HardLink => fs::symlink_metadata(&local_path).await,
+        Kind::File | Kind::Directory => fs::metadata(&local_path).await,
+    };
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(vec![Mismatch::Missing(path)])
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let reason = match entry.kind() {
+        Kind::File => verify_file(&local_path, entry, &metadata).await?,
+        Kind::Directory if !metadata.is_dir() => Some("not a directory".to_string()),
+        Kind::Directory => None,
+        Kind::Symlink => verify_symlink(&local_path, entry, &metadata).await?,
+        Kind::HardLink => verify_hard_link(dir, entry, &metadata).await?,
+    };
+    Ok(reason
+        .map(|reason| Mismatch::Differs(path, reason))
+        .into_iter()
+        .collect())
+}
+
+async fn verify_file(
+    path: &Path,
+    entry: &FileEntry,
+    metadata: &Metadata,
+) -> Result<Option<String>, Error> {
+    if !metadata.is_file() {
+        return Ok(Some("not a regular file".to_string()));
+    }
+    if metadata.len() as usize != entry.size() {
+        return Ok(Some(format!("size {} != {}", metadata.len(), entry.size())));
+    }
+    let expected = match entry.checksum() {
+        Some(checksum) => checksum.crc32c(),
+        None => return Ok(None),
+    };
+    let path = path.to_path_buf();
+    let actual = task::spawn_blocking(move || crc32c_of_file(&path)).await??;
+    if actual != expected {
+        return Ok(Some(format!("crc32c {:08x} != {:08x}", actual, expected)));
+    }
+    Ok(None)
+}
+
+async fn verify_symlink(
+    path: &Path,
+    entry: &FileEntry,
+    metadata: &Metadata,
+) -> Result<Option<String>, Error> {
+    if !metadata.file_type().is_symlink() {
+        return Ok(Some("not a symlink".to_string()));
+    }
+    let target = fs::read_link(path).await?;
+    let expected = entry.link().unwrap_or_default();
+    if target.as_os_str() != expected {
+        return Ok(Some(format!("link {} != {}", target.display(), expected)));
+    }
+    Ok(None)
+}
+
+async fn verify_hard_link(
+    dir: &Path,
+    entry: &FileEntry,
+    metadata: &Metadata,
+) -> Result<Option<String>, Error> {
+    let link = entry.link().ok_or("no link target in manifest")?;
+    let linked = match fs::symlink_metadata(dir.join(link)).await {
+        Ok(linked) => linked,
+        // reported as missing by the linked entry
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if (metadata.dev(), metadata.ino()) != (linked.dev(), linked.ino()) {
+        return Ok(Some(format!("not a hard link to {}", link)));
+    }
+    Ok(None)
+}